@@ -0,0 +1,129 @@
+//! mapping of buffer indices to virtual addresses, using a load base and a set of segments.
+
+use crate::{unwrap_or_panic, Error, Gravity, IndexRangeRef, IndexRef, IndexRefBuf};
+use std::ops::Range;
+
+/// a segment registered in a buffer, which maps a range of the buffer to consecutive virtual addresses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Segment {
+    range: IndexRangeRef,
+    address: u64,
+}
+impl Segment {
+    /// the same segment, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            range: self.range.rebind(old_buf_id, buf_id),
+            address: self.address,
+        }
+    }
+}
+
+impl<T> IndexRefBuf<T> {
+    /// the load base of the buffer, which is added to every virtual address.
+    pub fn load_base(&self) -> u64 {
+        self.load_base
+    }
+    /// sets the load base of the buffer, which is added to every virtual address.
+    pub fn set_load_base(&mut self, load_base: u64) {
+        self.load_base = load_base;
+    }
+    /// maps the given range of the buffer to the given virtual address, relative to the load base, and returns a range ref
+    /// to the mapped range. content inserted exactly at either boundary of the range becomes part of it.
+    ///
+    /// once any segment is added, only indices inside of segments have virtual addresses. without segments, the virtual
+    /// address of every index is the load base plus the index.
+    pub fn add_segment(&mut self, range: Range<usize>, address: u64) -> IndexRangeRef {
+        unwrap_or_panic(self.try_add_segment(range, address))
+    }
+    /// like `add_segment`, but returns an error instead of panicking.
+    pub fn try_add_segment(
+        &mut self,
+        range: Range<usize>,
+        address: u64,
+    ) -> Result<IndexRangeRef, Error> {
+        let range = self.try_create_range_ref_with_gravity(range, Gravity::Left, Gravity::Right)?;
+        self.segments.push(Segment { range, address });
+        Ok(range)
+    }
+    /// the file offset of the given index ref, which is just its index.
+    pub fn file_offset(&self, index_ref: IndexRef) -> usize {
+        self.read_index_ref(index_ref)
+    }
+    /// the virtual address of the given index ref.
+    /// an index ref at the end of a segment is considered part of it, so that it can be used to refer to the end of the
+    /// segment, unless another segment starts at the same index. if the index ref is in multiple segments, the first one
+    /// that was added is used.
+    pub fn virtual_address(&self, index_ref: IndexRef) -> u64 {
+        unwrap_or_panic(self.try_virtual_address(index_ref))
+    }
+    /// like `virtual_address`, but returns an error instead of panicking.
+    pub fn try_virtual_address(&self, index_ref: IndexRef) -> Result<u64, Error> {
+        let index = self.try_read_index_ref(index_ref)?;
+        let address = if self.segments.is_empty() {
+            Some(index as u64)
+        } else {
+            let segment_address = |include_end: bool| {
+                self.segments.iter().find_map(|segment| {
+                    let range = self.get_range_ref(segment.range)?;
+                    let contains = range.contains(&index) || (include_end && range.end == index);
+                    contains.then(|| segment.address.wrapping_add((index - range.start) as u64))
+                })
+            };
+            segment_address(false).or_else(|| segment_address(true))
+        };
+        address
+            .map(|address| self.load_base.wrapping_add(address))
+            .ok_or(Error::UnmappedIndex { index })
+    }
+    /// the distance between the virtual addresses of the given index refs, which is the displacement used by position
+    /// relative code at `from` to refer to `to`.
+    pub fn relative_address(&self, from: IndexRef, to: IndexRef) -> i128 {
+        unwrap_or_panic(self.try_relative_address(from, to))
+    }
+    /// like `relative_address`, but returns an error instead of panicking.
+    pub fn try_relative_address(&self, from: IndexRef, to: IndexRef) -> Result<i128, Error> {
+        Ok(self.try_virtual_address(to)? as i128 - self.try_virtual_address(from)? as i128)
+    }
+}
+
+#[test]
+pub fn make_sure_refs_resolve_to_virtual_addresses_through_segments() {
+    use crate::{Endianness, Fixup};
+
+    // a header which is not mapped, followed by code and data segments which are mapped far apart.
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 32]);
+    buf.set_load_base(0x400000);
+    let header = buf.create_index_ref(0);
+    let code = buf.add_segment(8..16, 0x1000);
+    let data = buf.add_segment(16..32, 0x3000);
+    // a rip relative displacement to a value, and a pointer to the start of the code.
+    let disp = buf.create_index_ref(10);
+    let next_insn = buf.create_index_ref(14);
+    let pointer = buf.create_index_ref(16);
+    let value = buf.create_index_ref(24);
+    buf.add_fixup(Fixup::address_relative(
+        disp,
+        next_insn,
+        value,
+        4,
+        Endianness::Little,
+    ));
+    buf.add_fixup(Fixup::address(pointer, code.start(), 8, Endianness::Little));
+
+    buf.insert_slice(9, &[0x90; 2]);
+    assert_eq!(buf.file_offset(value), 26);
+    assert_eq!(buf.virtual_address(value), 0x403008);
+    // the end of the code is also the start of the data, which takes precedence.
+    assert_eq!(buf.virtual_address(code.end()), 0x403000);
+    assert_eq!(buf.relative_address(next_insn, value), 0x2000);
+    assert_eq!(
+        buf.try_virtual_address(header),
+        Err(Error::UnmappedIndex { index: 0 })
+    );
+
+    buf.finalize().unwrap();
+    assert_eq!(&buf[12..16], &0x2000i32.to_le_bytes());
+    assert_eq!(&buf[18..26], &0x401000u64.to_le_bytes());
+    assert_eq!(buf.read_range_ref(data), 18..34);
+}
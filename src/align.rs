@@ -0,0 +1,229 @@
+//! alignment padding, which is resized whenever the content before it moves.
+
+use crate::{unwrap_or_panic, Error, Gravity, IndexRangeRef, IndexRefBuf};
+
+/// an alignment directive registered in a buffer.
+#[derive(Debug, Clone)]
+pub(crate) struct Alignment<T> {
+    padding: IndexRangeRef,
+    align: usize,
+    fill: Vec<T>,
+    /// builds the padding for a given index. stored with the directive, since resizing happens in edits of buffers of
+    /// any element type, while building padding requires cloning the fill pattern.
+    build_padding: fn(usize, usize, &[T]) -> Vec<T>,
+}
+impl<T> Alignment<T> {
+    /// the padding with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`, the
+    /// alignment and the fill pattern, which identify the directive regardless of the id of its buffer.
+    pub(crate) fn identity(&self, old_buf_id: u64, buf_id: u64) -> (IndexRangeRef, usize, &[T]) {
+        (
+            self.padding.rebind(old_buf_id, buf_id),
+            self.align,
+            &self.fill,
+        )
+    }
+}
+impl<T: Clone> Alignment<T> {
+    /// the same alignment, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            padding: self.padding.rebind(old_buf_id, buf_id),
+            ..self.clone()
+        }
+    }
+}
+
+impl<T: Clone> IndexRefBuf<T> {
+    /// inserts padding at the given index so that the content after it starts at a multiple of `align`, and returns a
+    /// range ref to the padding. the padding is filled by repeating the fill pattern from its start, for example `[0x90]`
+    /// for x86 `nop`s.
+    ///
+    /// the padding is resized by every edit which moves the content before it, so index refs after it always point to
+    /// their aligned indices. content inserted exactly at either boundary of the padding stays outside of it, so content
+    /// inserted at its end is the aligned content. if the padding is removed along with the content around it, the
+    /// alignment is reported once as an error by `update_alignments` or `finalize`, and then dropped.
+    pub fn create_alignment(&mut self, index: usize, align: usize, fill: &[T]) -> IndexRangeRef {
+        unwrap_or_panic(self.try_create_alignment(index, align, fill))
+    }
+    /// like `create_alignment`, but returns an error instead of panicking.
+    pub fn try_create_alignment(
+        &mut self,
+        index: usize,
+        align: usize,
+        fill: &[T],
+    ) -> Result<IndexRangeRef, Error> {
+        if align == 0 || fill.is_empty() {
+            return Err(Error::InvalidAlignment);
+        }
+        self.check_insertion_index(index)?;
+        let padding_elements = padding(index, align, fill);
+        self.try_insert_slice(index, &padding_elements)?;
+        let padding = self.try_create_range_ref_with_gravity(
+            index..index + padding_elements.len(),
+            Gravity::Right,
+            Gravity::Left,
+        )?;
+        // the directives are kept in the order of their padding, so edits only resize the padding after them.
+        let position = self.alignments.partition_point(|alignment| {
+            self.get_range_ref(alignment.padding)
+                .is_some_and(|existing| existing.start < index)
+        });
+        self.alignments.insert(
+            position,
+            Alignment {
+                padding,
+                align,
+                fill: fill.to_vec(),
+                build_padding: self::padding,
+            },
+        );
+        Ok(padding)
+    }
+}
+
+impl<T> IndexRefBuf<T> {
+    /// resizes the padding of every alignment directive according to the current index of its start, and returns an error
+    /// for every alignment whose padding was removed, which is then dropped.
+    /// since edits already resize the padding, this only has an effect on padding that was edited directly.
+    pub fn update_alignments(&mut self) -> Result<(), Vec<Error>> {
+        self.composite_edit(|buf| while buf.resize_alignments() {});
+        let errors = self.take_alignment_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// resizes the padding of alignment directives which no longer align the content after them, from the first one to
+    /// the last one, since resizing padding moves the padding after it. returns whether any padding was resized.
+    pub(crate) fn resize_alignments(&mut self) -> bool {
+        let mut resized = false;
+        for alignment_index in 0..self.alignments.len() {
+            resized |= self.resize_alignment(alignment_index) != 0;
+        }
+        resized
+    }
+    /// resizes the padding of a single alignment directive, and returns by how much its length changed.
+    fn resize_alignment(&mut self, alignment_index: usize) -> isize {
+        let alignment = &self.alignments[alignment_index];
+        let Some(current) = self.get_range_ref(alignment.padding) else {
+            return 0;
+        };
+        let padding_elements =
+            (alignment.build_padding)(current.start, alignment.align, &alignment.fill);
+        if padding_elements.len() == current.len() {
+            return 0;
+        }
+        // the boundaries are moved explicitly, since an empty padding would otherwise grow after its start.
+        let (padding, len) = (alignment.padding, padding_elements.len());
+        self.splice(current.clone(), padding_elements);
+        unwrap_or_panic(self.move_index_ref(padding.start(), current.start));
+        unwrap_or_panic(self.move_index_ref(padding.end(), current.start + len));
+        len as isize - current.len() as isize
+    }
+    /// resizes the padding of alignment directives after an edit at `start` which moved the content after it by
+    /// `offset` elements. the resizing edits are considered a part of the edit that caused them.
+    /// only padding which ends at or after the edit is checked, and once the content after the checked padding is back at
+    /// its previous index, the padding after it is still aligned.
+    pub(crate) fn realign_after_edit(&mut self, start: usize, offset: isize) {
+        if self.realigning || self.alignments.is_empty() {
+            return;
+        }
+        self.realigning = true;
+        self.composite_depth += 1;
+        // removed padding fails the predicate, which may only make the search start earlier.
+        let first = self.alignments.partition_point(|alignment| {
+            self.get_range_ref(alignment.padding)
+                .is_some_and(|padding| padding.end < start)
+        });
+        let mut offset = offset;
+        for alignment_index in first..self.alignments.len() {
+            if offset == 0 {
+                break;
+            }
+            offset += self.resize_alignment(alignment_index);
+        }
+        self.composite_depth -= 1;
+        self.realigning = false;
+    }
+    /// drops every alignment directive whose padding was removed, and returns an error for each of them, so each one is
+    /// only reported once.
+    pub(crate) fn take_alignment_errors(&mut self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        let mut alignments = std::mem::take(&mut self.alignments);
+        alignments.retain(|alignment| {
+            let removed = self.try_read_range_ref(alignment.padding).is_err();
+            if removed {
+                errors.push(Error::RemovedAlignment {
+                    padding: alignment.padding,
+                });
+            }
+            !removed
+        });
+        self.alignments = alignments;
+        errors
+    }
+}
+
+/// the padding that should be placed at the given index to align the content after it.
+fn padding<T: Clone>(index: usize, align: usize, fill: &[T]) -> Vec<T> {
+    let len = (align - index % align) % align;
+    fill.iter().cloned().cycle().take(len).collect()
+}
+
+#[test]
+pub fn make_sure_alignment_padding_follows_the_content_before_it() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xc3; 5]);
+    let padding = buf.create_alignment(5, 16, &[0x66, 0x90]);
+    let function = buf.create_index_ref(16);
+    buf.extend_from_slice(&[0x55, 0xc3]);
+    assert_eq!(buf.range_ref_slice(padding), &[0x66, 0x90].repeat(6)[..11]);
+
+    buf.insert_slice(0, &[0xcc; 3]);
+    buf.update_alignments().unwrap();
+    assert_eq!(buf.read_index_ref(function), 16);
+    assert_eq!(buf.read_range_ref(padding), 8..16);
+    assert_eq!(&buf[16..], &[0x55, 0xc3]);
+
+    buf.insert_slice(0, &[0xcc; 9]);
+    buf.finalize().unwrap();
+    assert_eq!(buf.read_index_ref(function), 32);
+    assert_eq!(buf.read_range_ref(padding), 17..32);
+}
+
+#[test]
+pub fn make_sure_alignment_padding_is_resized_by_every_edit() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xc3; 16]);
+    let padding = buf.create_alignment(16, 16, &[0x90]);
+    let function = buf.create_index_ref(16);
+    buf.extend_from_slice(&[0x55]);
+    buf.remove(15);
+    assert_eq!(buf.read_range_ref(padding), 15..16);
+    assert_eq!(buf.read_index_ref(function), 16);
+    assert_eq!(&buf[15..], &[0x90, 0x55]);
+
+    // removing the padding along with the content around it kills the alignment, which is reported.
+    buf.drain(14..17);
+    assert_eq!(buf.get_range_ref(padding), None);
+    assert_eq!(
+        buf.finalize(),
+        Err(vec![Error::RemovedAlignment { padding }])
+    );
+    assert_eq!(buf.finalize(), Ok(()));
+}
+
+#[test]
+pub fn make_sure_alignment_directives_created_out_of_order_are_all_resized() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xc3; 24]);
+    let second = buf.create_alignment(20, 8, &[0x90]);
+    let first = buf.create_alignment(4, 8, &[0x90]);
+    assert_eq!(buf.read_range_ref(first), 4..8);
+    assert_eq!(buf.read_range_ref(second), 24..24);
+
+    buf.insert_slice(0, &[0xcc; 3]);
+    assert_eq!(buf.read_range_ref(first), 7..8);
+    assert_eq!(buf.read_range_ref(second), 24..24);
+    buf.insert(10, 0xcc);
+    assert_eq!(buf.read_range_ref(first), 7..8);
+    assert_eq!(buf.read_range_ref(second), 25..32);
+}
@@ -0,0 +1,402 @@
+//! checksum fields, which are computed over a range of the buffer once its final content is known.
+
+use crate::{
+    fixup::{check_width, encode_int},
+    unwrap_or_panic, Endianness, Error, IndexRangeRef, IndexRef, IndexRefBuf,
+};
+use std::{
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    ops::Range,
+    sync::Arc,
+};
+
+/// a user supplied function which computes a checksum. it is shared between clones of the buffer, which may be sent to
+/// other threads.
+type ChecksumFn = dyn Fn(&[u8]) -> u64 + Send + Sync;
+
+/// the algorithm used to compute a checksum.
+#[derive(Clone)]
+pub enum ChecksumAlgorithm {
+    /// the CRC-32 used by zlib, PNG and ethernet, which is 4 bytes wide.
+    Crc32,
+    /// CRC-16/CCITT-FALSE, with polynomial 0x1021 and initial value 0xffff, which is 2 bytes wide.
+    Crc16,
+    /// the Adler-32 checksum used by zlib, which is 4 bytes wide.
+    Adler32,
+    /// the internet checksum of RFC 1071, computed over big endian words, which is 2 bytes wide.
+    Internet,
+    /// a user supplied checksum with the given width in bytes, which must be between 1 and 8. checksums with other
+    /// widths fail to be written.
+    Custom {
+        width: usize,
+        compute: Arc<ChecksumFn>,
+    },
+}
+impl ChecksumAlgorithm {
+    /// creates a user supplied checksum. the width is in bytes, and must be between 1 and 8.
+    pub fn custom(width: usize, compute: impl Fn(&[u8]) -> u64 + Send + Sync + 'static) -> Self {
+        unwrap_or_panic(Self::try_custom(width, compute))
+    }
+    /// like `custom`, but returns an error instead of panicking.
+    pub fn try_custom(
+        width: usize,
+        compute: impl Fn(&[u8]) -> u64 + Send + Sync + 'static,
+    ) -> Result<Self, Error> {
+        check_width(width)?;
+        Ok(ChecksumAlgorithm::Custom {
+            width,
+            compute: Arc::new(compute),
+        })
+    }
+    /// the width of the checksum, in bytes.
+    pub fn width(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::Crc32 | ChecksumAlgorithm::Adler32 => 4,
+            ChecksumAlgorithm::Crc16 | ChecksumAlgorithm::Internet => 2,
+            ChecksumAlgorithm::Custom { width, .. } => *width,
+        }
+    }
+    /// computes the checksum of the given data.
+    pub fn compute(&self, data: &[u8]) -> u64 {
+        match self {
+            ChecksumAlgorithm::Crc32 => {
+                let mut crc = 0xffffffffu32;
+                for &byte in data {
+                    crc ^= byte as u32;
+                    for _ in 0..8 {
+                        crc = if crc & 1 != 0 {
+                            (crc >> 1) ^ 0xedb88320
+                        } else {
+                            crc >> 1
+                        };
+                    }
+                }
+                (!crc) as u64
+            }
+            ChecksumAlgorithm::Crc16 => {
+                let mut crc = 0xffffu16;
+                for &byte in data {
+                    crc ^= (byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 {
+                            (crc << 1) ^ 0x1021
+                        } else {
+                            crc << 1
+                        };
+                    }
+                }
+                crc as u64
+            }
+            ChecksumAlgorithm::Adler32 => {
+                let (mut a, mut b) = (1u32, 0u32);
+                for &byte in data {
+                    a = (a + byte as u32) % 65521;
+                    b = (b + a) % 65521;
+                }
+                ((b << 16) | a) as u64
+            }
+            ChecksumAlgorithm::Internet => {
+                let mut sum = data
+                    .chunks(2)
+                    .map(|word| ((word[0] as u64) << 8) | word.get(1).copied().unwrap_or(0) as u64)
+                    .sum::<u64>();
+                while sum >> 16 != 0 {
+                    sum = (sum & 0xffff) + (sum >> 16);
+                }
+                !sum & 0xffff
+            }
+            ChecksumAlgorithm::Custom { compute, .. } => compute(data),
+        }
+    }
+}
+impl Debug for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChecksumAlgorithm::Crc32 => write!(f, "Crc32"),
+            ChecksumAlgorithm::Crc16 => write!(f, "Crc16"),
+            ChecksumAlgorithm::Adler32 => write!(f, "Adler32"),
+            ChecksumAlgorithm::Internet => write!(f, "Internet"),
+            ChecksumAlgorithm::Custom { width, .. } => {
+                f.debug_struct("Custom").field("width", width).finish()
+            }
+        }
+    }
+}
+/// user supplied checksums are only equal if they share the same function.
+impl PartialEq for ChecksumAlgorithm {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                ChecksumAlgorithm::Custom { width, compute },
+                ChecksumAlgorithm::Custom {
+                    width: other_width,
+                    compute: other_compute,
+                },
+            ) => width == other_width && Arc::ptr_eq(compute, other_compute),
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+impl Eq for ChecksumAlgorithm {}
+impl Hash for ChecksumAlgorithm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let ChecksumAlgorithm::Custom { width, compute } = self {
+            width.hash(state);
+            Arc::as_ptr(compute).cast::<()>().hash(state);
+        }
+    }
+}
+
+/// a checksum field, whose value is the checksum of a range of the buffer.
+///
+/// the field may lie inside of its own range, like in an IP header, in which case it is zeroed before the checksum is
+/// computed. the field may also lie inside of the range of another checksum, like a checksum of a chunk inside of a
+/// checksummed image, in which case it is written before the other checksum is computed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Checksum {
+    site: IndexRef,
+    range: IndexRangeRef,
+    algorithm: ChecksumAlgorithm,
+    endianness: Endianness,
+}
+impl Checksum {
+    /// creates a checksum of the given range, which is written at the given patch site.
+    pub fn new(
+        site: IndexRef,
+        range: IndexRangeRef,
+        algorithm: ChecksumAlgorithm,
+        endianness: Endianness,
+    ) -> Self {
+        Self {
+            site,
+            range,
+            algorithm,
+            endianness,
+        }
+    }
+    /// the index ref to the patch site, where the checksum is written.
+    pub fn site(&self) -> IndexRef {
+        self.site
+    }
+    /// the range ref of the checksummed range.
+    pub fn range(&self) -> IndexRangeRef {
+        self.range
+    }
+    /// the algorithm of the checksum.
+    pub fn algorithm(&self) -> &ChecksumAlgorithm {
+        &self.algorithm
+    }
+    /// the byte order of the written checksum.
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+    /// the same checksum, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            site: self.site.rebind(old_buf_id, buf_id),
+            range: self.range.rebind(old_buf_id, buf_id),
+            ..self.clone()
+        }
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// registers a checksum, which will be written by `write_checksums` or `finalize`.
+    pub fn add_checksum(&mut self, checksum: Checksum) {
+        self.checksums.push(checksum);
+    }
+    /// the registered checksums.
+    pub fn checksums(&self) -> &[Checksum] {
+        &self.checksums
+    }
+    /// removes all registered checksums.
+    pub fn clear_checksums(&mut self) {
+        self.checksums.clear();
+    }
+    /// computes and writes all registered checksums according to the current content of the buffer.
+    ///
+    /// a checksum whose range contains the field of another checksum is computed after the other checksum is written.
+    /// an error is returned for every checksum that could not be written, including checksums that depend on each other in
+    /// a cycle, and their fields are left unchanged.
+    pub fn write_checksums(&mut self) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
+        let mut fields = Vec::new();
+        for checksum_index in 0..self.checksums.len() {
+            match self.checksum_field(checksum_index) {
+                Ok(field) => fields.push(field),
+                Err(err) => errors.push(err),
+            }
+        }
+
+        // a checksum can be written once every other checksum whose field lies in its range was written.
+        let overlaps = |a: &Range<usize>, b: &Range<usize>| a.start < b.end && b.start < a.end;
+        let mut written = vec![false; fields.len()];
+        loop {
+            let ready = (0..fields.len()).find(|&i| {
+                !written[i]
+                    && (0..fields.len()).all(|j| {
+                        i == j || written[j] || !overlaps(&fields[j].site, &fields[i].range)
+                    })
+            });
+            let Some(i) = ready else {
+                break;
+            };
+            written[i] = true;
+            if let Err(err) = self.write_checksum(&fields[i]) {
+                errors.push(err);
+            }
+        }
+        errors.extend(
+            written
+                .iter()
+                .filter(|written| !**written)
+                .map(|_| Error::CyclicChecksum),
+        );
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// resolves the location of the field and of the range of a single checksum.
+    fn checksum_field(&self, checksum_index: usize) -> Result<ChecksumField, Error> {
+        let checksum = &self.checksums[checksum_index];
+        let site = self.try_read_index_ref(checksum.site)?;
+        check_width(checksum.algorithm.width())?;
+        let (start, end) = self.try_range_indices(&(site..site + checksum.algorithm.width()))?;
+        Ok(ChecksumField {
+            checksum_index,
+            site: start..end,
+            range: self.try_read_range_ref(checksum.range)?,
+        })
+    }
+    /// computes and writes a single checksum, zeroing its field first in case it lies inside of its own range.
+    fn write_checksum(&mut self, field: &ChecksumField) -> Result<(), Error> {
+        let checksum = &self.checksums[field.checksum_index];
+        let original = self.buf[field.site.clone()].to_vec();
+        self.buf[field.site.clone()].fill(0);
+        let value = checksum.algorithm.compute(&self.buf[field.range.clone()]);
+        let Some(bytes) = encode_int(
+            value as i128,
+            checksum.algorithm.width(),
+            checksum.endianness,
+            false,
+        ) else {
+            self.buf[field.site.clone()].copy_from_slice(&original);
+            return Err(Error::FixupOverflow {
+                site: field.site.start,
+                value: value as i128,
+                width: checksum.algorithm.width(),
+            });
+        };
+        self.buf[field.site.clone()].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// the resolved location of the field and of the range of a checksum.
+struct ChecksumField {
+    checksum_index: usize,
+    site: Range<usize>,
+    range: Range<usize>,
+}
+
+#[test]
+pub fn make_sure_checksum_algorithms_match_their_check_values() {
+    let data = b"123456789";
+    assert_eq!(ChecksumAlgorithm::Crc32.compute(data), 0xcbf43926);
+    assert_eq!(ChecksumAlgorithm::Crc16.compute(data), 0x29b1);
+    assert_eq!(ChecksumAlgorithm::Adler32.compute(b"Wikipedia"), 0x11e60398);
+    // the example of RFC 1071.
+    assert_eq!(
+        ChecksumAlgorithm::Internet.compute(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+        !0xddf2 & 0xffff
+    );
+}
+
+#[test]
+pub fn make_sure_nested_checksums_are_written_in_dependency_order() {
+    // an image checksummed by a trailing crc32, containing a header which checksums itself and a chunk with its own crc16.
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 16]);
+    let image = buf.create_range_ref(0..12);
+    let header_site = buf.create_index_ref(2);
+    let header = buf.create_range_ref(0..4);
+    let chunk_site = buf.create_index_ref(8);
+    let chunk = buf.create_range_ref(4..8);
+    let image_site = buf.create_index_ref(12);
+    buf.add_checksum(Checksum::new(
+        image_site,
+        image,
+        ChecksumAlgorithm::Crc32,
+        Endianness::Big,
+    ));
+    buf.add_checksum(Checksum::new(
+        chunk_site,
+        chunk,
+        ChecksumAlgorithm::Crc16,
+        Endianness::Big,
+    ));
+    buf.add_checksum(Checksum::new(
+        header_site,
+        header,
+        ChecksumAlgorithm::Internet,
+        Endianness::Big,
+    ));
+
+    buf.insert_slice(6, &[1, 2, 3, 4]);
+    buf.splice(0..2, [0x45, 0x00]);
+    buf.finalize().unwrap();
+    let chunk_crc = ChecksumAlgorithm::Crc16.compute(&[0, 0, 1, 2, 3, 4, 0, 0]) as u16;
+    assert_eq!(&buf[12..14], &chunk_crc.to_be_bytes());
+    assert_eq!(ChecksumAlgorithm::Internet.compute(&buf[..4]), 0);
+    let image_crc = ChecksumAlgorithm::Crc32.compute(&buf[..16]) as u32;
+    assert_eq!(&buf[16..], &image_crc.to_be_bytes());
+
+    // a checksum whose field is covered by a checksum covering its own range can't be ordered.
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 4]);
+    let (first, second) = (buf.create_index_ref(0), buf.create_index_ref(2));
+    let (first_range, second_range) = (buf.create_range_ref(2..4), buf.create_range_ref(0..2));
+    buf.add_checksum(Checksum::new(
+        first,
+        first_range,
+        ChecksumAlgorithm::Crc16,
+        Endianness::Big,
+    ));
+    buf.add_checksum(Checksum::new(
+        second,
+        second_range,
+        ChecksumAlgorithm::Crc16,
+        Endianness::Big,
+    ));
+    assert_eq!(
+        buf.write_checksums(),
+        Err(vec![Error::CyclicChecksum, Error::CyclicChecksum])
+    );
+}
+
+#[test]
+pub fn make_sure_custom_checksums_are_validated_and_thread_safe() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<IndexRefBuf>();
+
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 12]);
+    let (site, range) = (buf.create_index_ref(0), buf.create_range_ref(0..12));
+    buf.add_checksum(Checksum::new(
+        site,
+        range,
+        ChecksumAlgorithm::Custom {
+            width: 9,
+            compute: Arc::new(|data: &[u8]| data.len() as u64),
+        },
+        Endianness::Little,
+    ));
+    assert_eq!(
+        buf.write_checksums(),
+        Err(vec![Error::InvalidWidth { width: 9 }])
+    );
+    assert_eq!(&buf[..], &[0; 12]);
+    assert!(ChecksumAlgorithm::try_custom(16, |_| 0).is_err());
+}
@@ -0,0 +1,138 @@
+//! a cursor for sequential emission into a buffer, whose position is tracked by an index ref.
+
+use crate::{unwrap_or_panic, ByteRepr, Endianness, Error, Gravity, IndexRef, IndexRefBuf};
+
+/// a cursor which inserts content at its position in a buffer and advances past it.
+///
+/// the position of the cursor is an index ref with `Gravity::Right`, so it is kept up to date by all edits, and it stays
+/// after content which is inserted at it. the index ref is released when the cursor is dropped.
+#[derive(Debug)]
+pub struct IndexRefCursor<'a, T = u8> {
+    buf: &'a mut IndexRefBuf<T>,
+    position: IndexRef,
+}
+
+impl<T> IndexRefBuf<T> {
+    /// creates a cursor at the given index.
+    pub fn cursor_at(&mut self, index: usize) -> IndexRefCursor<'_, T> {
+        unwrap_or_panic(self.try_cursor_at(index))
+    }
+    /// like `cursor_at`, but returns an error instead of panicking.
+    pub fn try_cursor_at(&mut self, index: usize) -> Result<IndexRefCursor<'_, T>, Error> {
+        let position = self.try_create_index_ref_with_gravity(index, Gravity::Right)?;
+        Ok(IndexRefCursor {
+            buf: self,
+            position,
+        })
+    }
+    /// creates a cursor at the end of the buffer.
+    pub fn cursor_at_end(&mut self) -> IndexRefCursor<'_, T> {
+        self.cursor_at(self.len())
+    }
+}
+
+impl<T> IndexRefCursor<'_, T> {
+    /// the buffer of the cursor.
+    pub fn buf(&self) -> &IndexRefBuf<T> {
+        self.buf
+    }
+    /// the current index of the cursor.
+    pub fn position(&self) -> usize {
+        self.buf.read_index_ref(self.position)
+    }
+    /// moves the cursor to the given index.
+    pub fn seek_to(&mut self, index: usize) {
+        unwrap_or_panic(self.try_seek_to(index))
+    }
+    /// like `seek_to`, but returns an error instead of panicking.
+    pub fn try_seek_to(&mut self, index: usize) -> Result<(), Error> {
+        self.buf.move_index_ref(self.position, index)
+    }
+    /// moves the cursor to the current index of the given index ref.
+    pub fn seek_to_ref(&mut self, index_ref: IndexRef) {
+        unwrap_or_panic(self.try_seek_to_ref(index_ref))
+    }
+    /// like `seek_to_ref`, but returns an error instead of panicking.
+    pub fn try_seek_to_ref(&mut self, index_ref: IndexRef) -> Result<(), Error> {
+        let index = self.buf.try_read_index_ref(index_ref)?;
+        self.try_seek_to(index)
+    }
+    /// creates an index ref with `Gravity::Left` at the current index of the cursor, so it keeps pointing to the content
+    /// emitted right after it is created.
+    pub fn create_ref(&mut self) -> IndexRef {
+        self.create_ref_with_gravity(Gravity::Left)
+    }
+    /// creates an index ref with the given gravity at the current index of the cursor.
+    /// an index ref with `Gravity::Left` stays before content which is emitted later, so it marks the end of the content
+    /// emitted so far, while an index ref with `Gravity::Right` moves with the cursor until the cursor is moved elsewhere.
+    pub fn create_ref_with_gravity(&mut self, gravity: Gravity) -> IndexRef {
+        let index = self.position();
+        self.buf.create_index_ref_with_gravity(index, gravity)
+    }
+    /// inserts the given element at the cursor, and advances the cursor past it.
+    pub fn emit_one(&mut self, element: T) {
+        let index = self.position();
+        self.buf.insert(index, element);
+    }
+    /// inserts the given elements at the cursor, and advances the cursor past them.
+    pub fn emit(&mut self, elements: &[T])
+    where
+        T: Clone,
+    {
+        let index = self.position();
+        self.buf.insert_slice(index, elements);
+    }
+}
+
+impl IndexRefCursor<'_, u8> {
+    /// inserts the given value at the cursor, and advances the cursor past it.
+    pub fn emit_value<V: ByteRepr>(&mut self, value: V, endianness: Endianness) {
+        let mut bytes = vec![0; V::SIZE];
+        value.write_bytes(&mut bytes, endianness);
+        self.emit(&bytes);
+    }
+    /// inserts the given value as a minimal unsigned LEB128 integer at the cursor, and advances the cursor past it.
+    pub fn emit_uleb128(&mut self, value: u64) {
+        let index = self.position();
+        self.buf.insert_uleb128(index, value);
+    }
+    /// inserts the given value as a minimal signed LEB128 integer at the cursor, and advances the cursor past it.
+    pub fn emit_sleb128(&mut self, value: i64) {
+        let index = self.position();
+        self.buf.insert_sleb128(index, value);
+    }
+}
+
+impl<T> Drop for IndexRefCursor<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.buf.try_release_index_ref(self.position);
+    }
+}
+
+#[test]
+pub fn make_sure_cursors_emit_sequentially() {
+    use crate::Fixup;
+
+    let mut buf = IndexRefBuf::from_vec(vec![0xc3]);
+    let ret = buf.create_index_ref(0);
+    let mut cursor = buf.cursor_at(0);
+    cursor.emit(&[0xe8]);
+    let call_site = cursor.create_ref_with_gravity(Gravity::Left);
+    cursor.emit_value(0u32, Endianness::Little);
+    let following = cursor.create_ref();
+    cursor.emit_one(0x90);
+    assert_eq!(cursor.position(), 6);
+    cursor.seek_to_ref(call_site);
+    assert_eq!(cursor.position(), 1);
+
+    // going back to emit a prefix moves everything after it.
+    cursor.seek_to(0);
+    cursor.emit_uleb128(300);
+    assert_eq!(cursor.position(), 2);
+    drop(cursor);
+
+    buf.add_fixup(Fixup::relative(call_site, ret, 4, Endianness::Little).with_addend(-4));
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..], &[0xac, 0x02, 0xe8, 1, 0, 0, 0, 0x90, 0xc3]);
+    assert_eq!(buf.read_index_ref(following), 7);
+}
@@ -0,0 +1,114 @@
+use std::fmt::Display;
+
+use crate::IndexRangeRef;
+
+/// an error returned by the fallible operations of an index ref buffer.
+/// an operation which returns an error leaves the buffer unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Error {
+    /// the index is out of bound of the buffer.
+    OutOfBounds { index: usize, len: usize },
+    /// the range is either reversed, or out of bound of the buffer.
+    InvalidRange {
+        start: usize,
+        end: usize,
+        len: usize,
+    },
+    /// the index ref or symbol belongs to a different buffer.
+    ForeignRef,
+    /// the index ref was released.
+    ReleasedRef,
+    /// the target of the index ref was deleted.
+    DeletedTarget,
+    /// the value of a fixup or of a field does not fit in its width.
+    FixupOverflow {
+        site: usize,
+        value: i128,
+        width: usize,
+    },
+    /// the width of a field is not supported by its encoding, which is between 1 and 8 bytes for fixed width fields, and
+    /// between 1 and 18 bytes for padded LEB128 fields.
+    InvalidWidth { width: usize },
+    /// the variants of a relaxable instruction are either empty, or not ordered from the shortest to the longest, or the
+    /// field of a variant is out of bound of its template.
+    InvalidRelaxVariants,
+    /// an expression refers to a symbol which was declared but never defined.
+    UndefinedSymbol,
+    /// the definition of a symbol refers to itself, either directly or through other symbols.
+    CyclicExpression,
+    /// an expression divides by zero.
+    DivisionByZero,
+    /// an arithmetic operation in an expression overflows.
+    ArithmeticOverflow,
+    /// a checksum depends on itself through the fields of other checksums in its range.
+    CyclicChecksum,
+    /// the alignment of an alignment directive is zero, or its fill pattern is empty.
+    InvalidAlignment,
+    /// the padding of an alignment directive was removed, so the directive was dropped.
+    RemovedAlignment { padding: IndexRangeRef },
+    /// the index is not inside of any segment, so it has no virtual address.
+    UnmappedIndex { index: usize },
+    /// the LEB128 integer at the index is truncated, or doesn't fit in 64 bits.
+    InvalidVarint { index: usize },
+    /// offsets can't be mapped, since edits are not being recorded.
+    NotJournaling,
+    /// the layout was computed before sections were added or resized, so it no longer matches the sectioned buffer.
+    StaleLayout,
+    /// the layout of the buffer keeps changing, since the sizes of its fields depend on each other in a cycle.
+    LayoutDidNotConverge,
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::OutOfBounds { index, len } => write!(
+                f,
+                "index {} is out of bound of buffer with length {}",
+                index, len
+            ),
+            Error::InvalidRange { start, end, len } => write!(
+                f,
+                "range {}..{} is invalid for buffer with length {}",
+                start, end, len
+            ),
+            Error::ForeignRef => write!(f, "the index ref or symbol belongs to a different buffer"),
+            Error::ReleasedRef => write!(f, "the index ref was released"),
+            Error::DeletedTarget => write!(f, "the target of the index ref was deleted"),
+            Error::FixupOverflow { site, value, width } => write!(
+                f,
+                "value {} of field at index {} does not fit in {} bytes",
+                value, site, width
+            ),
+            Error::InvalidWidth { width } => {
+                write!(f, "field width {} is not supported by its encoding", width)
+            }
+            Error::InvalidRelaxVariants => write!(
+                f,
+                "relax variants must be non empty, ordered by length, and have their fields inside of their templates"
+            ),
+            Error::UndefinedSymbol => write!(f, "the expression refers to an undefined symbol"),
+            Error::CyclicExpression => write!(f, "the definition of a symbol refers to itself"),
+            Error::DivisionByZero => write!(f, "the expression divides by zero"),
+            Error::ArithmeticOverflow => {
+                write!(f, "an arithmetic operation in the expression overflows")
+            }
+            Error::CyclicChecksum => write!(f, "the checksums depend on each other in a cycle"),
+            Error::InvalidAlignment => write!(
+                f,
+                "alignment must be non zero and have a non empty fill pattern"
+            ),
+            Error::RemovedAlignment { .. } => {
+                write!(f, "the padding of an alignment directive was removed")
+            }
+            Error::UnmappedIndex { index } => {
+                write!(f, "index {} is not mapped by any segment", index)
+            }
+            Error::InvalidVarint { index } => {
+                write!(f, "invalid LEB128 integer at index {}", index)
+            }
+            Error::NotJournaling => write!(f, "edits are not being recorded"),
+            Error::StaleLayout => write!(f, "the layout does not match the current sections"),
+            Error::LayoutDidNotConverge => write!(f, "the layout of the buffer did not converge"),
+        }
+    }
+}
+impl std::error::Error for Error {}
@@ -0,0 +1,274 @@
+//! symbolic expressions over index refs and constants, which are evaluated when the buffer is finalized.
+
+use crate::{unwrap_or_panic, Error, IndexRef, IndexRefBuf};
+use std::ops::{Add, BitAnd, BitOr, Div, Mul, Neg, Shl, Shr, Sub};
+
+/// a symbolic expression over index refs, symbols and constants.
+///
+/// expressions are built using the arithmetic operators, for example `(Expr::from(end) - start) / 4`, and are evaluated
+/// using the current indices of the index refs that they contain. evaluation is done using 128 bit integers, and fails if
+/// an operation overflows.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Expr {
+    Const(i64),
+    Ref(IndexRef),
+    /// the virtual address of an index ref, according to the load base and segments of the buffer.
+    Address(IndexRef),
+    Symbol(Symbol),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Neg(Box<Expr>),
+}
+impl Expr {
+    /// the virtual address of the given index ref, as opposed to `Expr::from`, which gives its index.
+    pub fn address(index_ref: IndexRef) -> Self {
+        Expr::Address(index_ref)
+    }
+    /// the same expression, but with its index refs and symbols moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        let bin = |a: &Expr, b: &Expr| {
+            (
+                Box::new(a.rebind(old_buf_id, buf_id)),
+                Box::new(b.rebind(old_buf_id, buf_id)),
+            )
+        };
+        match self {
+            Expr::Const(value) => Expr::Const(*value),
+            Expr::Ref(index_ref) => Expr::Ref(index_ref.rebind(old_buf_id, buf_id)),
+            Expr::Address(index_ref) => Expr::Address(index_ref.rebind(old_buf_id, buf_id)),
+            Expr::Symbol(symbol) if symbol.buf_id == old_buf_id => {
+                Expr::Symbol(Symbol { buf_id, ..*symbol })
+            }
+            Expr::Symbol(symbol) => Expr::Symbol(*symbol),
+            Expr::Add(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Add(a, b)
+            }
+            Expr::Sub(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Sub(a, b)
+            }
+            Expr::Mul(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Mul(a, b)
+            }
+            Expr::Div(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Div(a, b)
+            }
+            Expr::Shl(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Shl(a, b)
+            }
+            Expr::Shr(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Shr(a, b)
+            }
+            Expr::And(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::And(a, b)
+            }
+            Expr::Or(a, b) => {
+                let (a, b) = bin(a, b);
+                Expr::Or(a, b)
+            }
+            Expr::Neg(a) => Expr::Neg(Box::new(a.rebind(old_buf_id, buf_id))),
+        }
+    }
+}
+impl From<i64> for Expr {
+    fn from(value: i64) -> Self {
+        Expr::Const(value)
+    }
+}
+impl From<IndexRef> for Expr {
+    fn from(index_ref: IndexRef) -> Self {
+        Expr::Ref(index_ref)
+    }
+}
+impl From<Symbol> for Expr {
+    fn from(symbol: Symbol) -> Self {
+        Expr::Symbol(symbol)
+    }
+}
+macro_rules! impl_binary_op {
+    ($trait:ident, $method:ident, $variant:ident) => {
+        impl<R: Into<Expr>> $trait<R> for Expr {
+            type Output = Expr;
+
+            fn $method(self, rhs: R) -> Expr {
+                Expr::$variant(Box::new(self), Box::new(rhs.into()))
+            }
+        }
+    };
+}
+impl_binary_op!(Add, add, Add);
+impl_binary_op!(Sub, sub, Sub);
+impl_binary_op!(Mul, mul, Mul);
+impl_binary_op!(Div, div, Div);
+impl_binary_op!(Shl, shl, Shl);
+impl_binary_op!(Shr, shr, Shr);
+impl_binary_op!(BitAnd, bitand, And);
+impl_binary_op!(BitOr, bitor, Or);
+impl Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        Expr::Neg(Box::new(self))
+    }
+}
+
+/// a named value in a buffer, defined by an expression which may refer to other symbols.
+/// symbols allow sharing values between expressions, and allow declaring a value before it can be defined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Symbol {
+    buf_id: u64,
+    symbol_index: usize,
+}
+
+impl<T> IndexRefBuf<T> {
+    /// declares a new symbol without defining it. it must be defined using `define_symbol` before it is evaluated.
+    pub fn declare_symbol(&mut self) -> Symbol {
+        self.symbols.push(None);
+        Symbol {
+            buf_id: self.id,
+            symbol_index: self.symbols.len() - 1,
+        }
+    }
+    /// creates a new symbol defined by the given expression.
+    pub fn create_symbol(&mut self, value: impl Into<Expr>) -> Symbol {
+        let symbol = self.declare_symbol();
+        self.define_symbol(symbol, value);
+        symbol
+    }
+    /// defines or redefines the given symbol.
+    pub fn define_symbol(&mut self, symbol: Symbol, value: impl Into<Expr>) {
+        unwrap_or_panic(self.try_define_symbol(symbol, value))
+    }
+    /// like `define_symbol`, but returns an error instead of panicking.
+    pub fn try_define_symbol(
+        &mut self,
+        symbol: Symbol,
+        value: impl Into<Expr>,
+    ) -> Result<(), Error> {
+        if symbol.buf_id != self.id {
+            return Err(Error::ForeignRef);
+        }
+        self.symbols[symbol.symbol_index] = Some(value.into());
+        Ok(())
+    }
+    /// evaluates the given expression using the current indices of its index refs.
+    pub fn evaluate(&self, expr: &Expr) -> Result<i128, Error> {
+        self.evaluate_inner(expr, &mut Vec::new())
+    }
+    /// evaluates the given expression, where `evaluating` is the stack of symbols currently being evaluated, used to detect
+    /// cyclic definitions.
+    fn evaluate_inner(&self, expr: &Expr, evaluating: &mut Vec<usize>) -> Result<i128, Error> {
+        let mut bin = |a: &Expr, b: &Expr| -> Result<(i128, i128), Error> {
+            Ok((
+                self.evaluate_inner(a, evaluating)?,
+                self.evaluate_inner(b, evaluating)?,
+            ))
+        };
+        let shift_amount = |amount: i128| -> Result<u32, Error> {
+            u32::try_from(amount)
+                .ok()
+                .filter(|amount| *amount < 128)
+                .ok_or(Error::ArithmeticOverflow)
+        };
+        let value = match expr {
+            Expr::Const(value) => Some(*value as i128),
+            Expr::Ref(index_ref) => Some(self.try_read_index_ref(*index_ref)? as i128),
+            Expr::Address(index_ref) => Some(self.try_virtual_address(*index_ref)? as i128),
+            Expr::Symbol(symbol) => {
+                if symbol.buf_id != self.id {
+                    return Err(Error::ForeignRef);
+                }
+                if evaluating.contains(&symbol.symbol_index) {
+                    return Err(Error::CyclicExpression);
+                }
+                let definition = self.symbols[symbol.symbol_index]
+                    .as_ref()
+                    .ok_or(Error::UndefinedSymbol)?;
+                evaluating.push(symbol.symbol_index);
+                let value = self.evaluate_inner(definition, evaluating)?;
+                evaluating.pop();
+                Some(value)
+            }
+            Expr::Add(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_add(b)
+            }
+            Expr::Sub(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_sub(b)
+            }
+            Expr::Mul(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_mul(b)
+            }
+            Expr::Div(a, b) => {
+                let (a, b) = bin(a, b)?;
+                if b == 0 {
+                    return Err(Error::DivisionByZero);
+                }
+                a.checked_div(b)
+            }
+            Expr::Shl(a, b) => {
+                let (a, b) = bin(a, b)?;
+                let shift = shift_amount(b)?;
+                // `checked_shl` only checks the shift amount, so bits shifted out are detected by shifting back.
+                a.checked_shl(shift).filter(|&value| value >> shift == a)
+            }
+            Expr::Shr(a, b) => {
+                let (a, b) = bin(a, b)?;
+                a.checked_shr(shift_amount(b)?)
+            }
+            Expr::And(a, b) => {
+                let (a, b) = bin(a, b)?;
+                Some(a & b)
+            }
+            Expr::Or(a, b) => {
+                let (a, b) = bin(a, b)?;
+                Some(a | b)
+            }
+            Expr::Neg(a) => self.evaluate_inner(a, evaluating)?.checked_neg(),
+        };
+        value.ok_or(Error::ArithmeticOverflow)
+    }
+}
+
+#[test]
+pub fn make_sure_expressions_are_evaluated_with_current_indices() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 8]);
+    let start = buf.create_index_ref(2);
+    let end = buf.create_index_ref(6);
+    let word_count = buf.create_symbol((Expr::from(end) - start) / 4);
+    let flags = (Expr::from(word_count) << 4) | 0x3;
+    assert_eq!(buf.evaluate(&flags), Ok(0x13));
+
+    buf.insert_slice(4, &[0; 4]);
+    assert_eq!(buf.evaluate(&flags), Ok(0x23));
+    assert_eq!(
+        buf.evaluate(&(Expr::from(start) / (Expr::from(end) - end))),
+        Err(Error::DivisionByZero)
+    );
+
+    let a = buf.declare_symbol();
+    let b = buf.create_symbol(Expr::from(a) + 1);
+    assert_eq!(buf.evaluate(&b.into()), Err(Error::UndefinedSymbol));
+    buf.define_symbol(a, Expr::from(b) - 1);
+    assert_eq!(buf.evaluate(&a.into()), Err(Error::CyclicExpression));
+
+    assert_eq!(buf.evaluate(&(Expr::from(-3) << 125)), Ok(-3 << 125));
+    assert_eq!(
+        buf.evaluate(&(Expr::from(3) << 127)),
+        Err(Error::ArithmeticOverflow)
+    );
+}
@@ -0,0 +1,54 @@
+//! the finalize pass, which runs every registered layout and patching step of the buffer in dependency order.
+
+use crate::{Error, IndexRefBuf};
+
+/// the maximal number of consecutive layout rounds in which no relaxable instruction grows, before the layout is
+/// considered to be oscillating.
+const MAX_STALLED_LAYOUT_ROUNDS: usize = 64;
+
+impl IndexRefBuf<u8> {
+    /// finalizes the buffer, by first computing the final layout, and then writing all fields against it.
+    ///
+    /// the layout is computed by repeatedly growing relaxable instructions, resizing length field headers and resizing
+    /// alignment padding, until none of them changes the layout. relaxable instructions only ever grow through a finite
+    /// number of variants, and the other steps usually reach a fixed point after a single round when nothing else changes.
+    /// but, the sizes of length field headers and of alignment padding may depend on each other in a cycle, so if the
+    /// layout keeps changing without any instruction growing, an error is returned and the current layout is used.
+    /// then, the final encodings of relaxable instructions, the length field headers and the fixups are written, and
+    /// finally the checksums are computed over the final content.
+    ///
+    /// every step runs even if a previous one failed, and the errors of all steps are returned together.
+    pub fn finalize(&mut self) -> Result<(), Vec<Error>> {
+        self.composite_edit(|buf| {
+            let mut errors = Vec::new();
+            let mut stalled_rounds = 0;
+            loop {
+                let grown = buf.relax_round();
+                let resized = buf.resize_length_fields();
+                let realigned = buf.resize_alignments();
+                if !grown && !resized && !realigned {
+                    break;
+                }
+                stalled_rounds = if grown { 0 } else { stalled_rounds + 1 };
+                if stalled_rounds == MAX_STALLED_LAYOUT_ROUNDS {
+                    errors.push(Error::LayoutDidNotConverge);
+                    break;
+                }
+            }
+            errors.extend(buf.take_alignment_errors());
+            errors.extend(buf.write_relaxables());
+            errors.extend(buf.write_length_fields());
+            if let Err(fixup_errors) = buf.resolve_fixups() {
+                errors.extend(fixup_errors);
+            }
+            if let Err(checksum_errors) = buf.write_checksums() {
+                errors.extend(checksum_errors);
+            }
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        })
+    }
+}
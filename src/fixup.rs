@@ -0,0 +1,302 @@
+//! fixups, which patch encoded offsets into the buffer once the final location of their targets is known.
+
+use crate::{unwrap_or_panic, Error, Expr, IndexRef, IndexRefBuf};
+
+/// the byte order of an encoded value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endianness {
+    Little,
+    Big,
+}
+
+/// decides how the value which is written by a fixup is computed from the value of its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FixupKind {
+    /// the value is the value of the target, encoded as an unsigned integer.
+    Absolute,
+    /// the value is the value of the target, encoded as a signed integer.
+    SignedAbsolute,
+    /// the value is the distance from the patch site to the target, encoded as a signed integer.
+    Relative,
+}
+impl FixupKind {
+    /// computes the value of a fixup of this kind with the given patch site, target value and addend, or returns `None`
+    /// if it overflows.
+    pub(crate) fn value(self, site: usize, target: i128, addend: i64) -> Option<i128> {
+        let value = match self {
+            FixupKind::Absolute | FixupKind::SignedAbsolute => target,
+            FixupKind::Relative => target.checked_sub(site as i128)?,
+        };
+        value.checked_add(addend as i128)
+    }
+    /// checks if values of this kind are encoded as signed integers.
+    pub(crate) fn is_signed(self) -> bool {
+        self != FixupKind::Absolute
+    }
+}
+
+/// a request to write the value of a target into the buffer at some patch site.
+///
+/// the target is an expression, which is usually just an index ref, in which case its value is the index of the index
+/// ref. the value which is written is computed from the value of the target according to the kind of the fixup, plus an
+/// addend. for example, an x86 `jmp rel32` is a relative fixup with a width of 4 and an addend of -4, since the
+/// displacement is relative to the end of the instruction.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Fixup {
+    site: IndexRef,
+    target: Expr,
+    width: usize,
+    endianness: Endianness,
+    kind: FixupKind,
+    addend: i64,
+}
+impl Fixup {
+    /// creates a fixup of the given kind. the width is in bytes, and must be between 1 and 8.
+    pub fn new(
+        site: IndexRef,
+        target: impl Into<Expr>,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Self {
+        unwrap_or_panic(Self::try_new(site, target, width, endianness, kind))
+    }
+    /// like `new`, but returns an error instead of panicking.
+    pub fn try_new(
+        site: IndexRef,
+        target: impl Into<Expr>,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Result<Self, Error> {
+        check_width(width)?;
+        Ok(Self {
+            site,
+            target: target.into(),
+            width,
+            endianness,
+            kind,
+            addend: 0,
+        })
+    }
+    /// creates a fixup which writes the value of the target.
+    pub fn absolute(
+        site: IndexRef,
+        target: impl Into<Expr>,
+        width: usize,
+        endianness: Endianness,
+    ) -> Self {
+        Self::new(site, target, width, endianness, FixupKind::Absolute)
+    }
+    /// creates a fixup which writes the distance from the patch site to the target.
+    pub fn relative(
+        site: IndexRef,
+        target: impl Into<Expr>,
+        width: usize,
+        endianness: Endianness,
+    ) -> Self {
+        Self::new(site, target, width, endianness, FixupKind::Relative)
+    }
+    /// creates a fixup which writes the virtual address of the target.
+    pub fn address(site: IndexRef, target: IndexRef, width: usize, endianness: Endianness) -> Self {
+        Self::absolute(site, Expr::address(target), width, endianness)
+    }
+    /// creates a fixup which writes the distance between the virtual addresses of `from` and `to`, as used by position
+    /// relative code. unlike a relative fixup, this is correct even when the patch site and the target are in segments
+    /// which are mapped at different distances from each other than in the buffer.
+    pub fn address_relative(
+        site: IndexRef,
+        from: IndexRef,
+        to: IndexRef,
+        width: usize,
+        endianness: Endianness,
+    ) -> Self {
+        Self::new(
+            site,
+            Expr::address(to) - Expr::address(from),
+            width,
+            endianness,
+            FixupKind::SignedAbsolute,
+        )
+    }
+    /// adds the given addend to the value of the fixup.
+    pub fn with_addend(self, addend: i64) -> Self {
+        Self { addend, ..self }
+    }
+    /// the index ref to the patch site, where the value is written.
+    pub fn site(&self) -> IndexRef {
+        self.site
+    }
+    /// the target of the fixup.
+    pub fn target(&self) -> &Expr {
+        &self.target
+    }
+    /// the width of the encoded value, in bytes.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    /// the byte order of the encoded value.
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+    /// the kind of the fixup.
+    pub fn kind(&self) -> FixupKind {
+        self.kind
+    }
+    /// the addend of the fixup.
+    pub fn addend(&self) -> i64 {
+        self.addend
+    }
+    /// the same fixup, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            site: self.site.rebind(old_buf_id, buf_id),
+            target: self.target.rebind(old_buf_id, buf_id),
+            ..self.clone()
+        }
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// registers a fixup, which will be written by `resolve_fixups` or `finalize`.
+    pub fn add_fixup(&mut self, fixup: Fixup) {
+        self.fixups.push(fixup);
+    }
+    /// the registered fixups.
+    pub fn fixups(&self) -> &[Fixup] {
+        &self.fixups
+    }
+    /// removes all registered fixups.
+    pub fn clear_fixups(&mut self) {
+        self.fixups.clear();
+    }
+    /// writes the values of all registered fixups into the buffer, according to the current locations of their patch
+    /// sites and targets. the fixups stay registered, so this can be called again after further edits.
+    ///
+    /// every fixup that can be resolved is written. if some fixups could not be resolved, for example because their value
+    /// overflows their width, an error is returned for each of them, and their patch sites are left unchanged.
+    pub fn resolve_fixups(&mut self) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
+        for fixup_index in 0..self.fixups.len() {
+            let fixup = self.fixups[fixup_index].clone();
+            if let Err(err) = self.resolve_fixup(&fixup) {
+                errors.push(err);
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// writes the value of a single fixup into the buffer.
+    fn resolve_fixup(&mut self, fixup: &Fixup) -> Result<(), Error> {
+        let site = self.try_read_index_ref(fixup.site)?;
+        let target = self.evaluate(&fixup.target)?;
+        let value = fixup
+            .kind
+            .value(site, target, fixup.addend)
+            .ok_or(Error::ArithmeticOverflow)?;
+        let bytes = encode_int(value, fixup.width, fixup.endianness, fixup.kind.is_signed())
+            .ok_or(Error::FixupOverflow {
+                site,
+                value,
+                width: fixup.width,
+            })?;
+        let (start, end) = self.try_range_indices(&(site..site + fixup.width))?;
+        self.buf[start..end].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+
+/// checks that the given width of a field is between 1 and 8 bytes.
+pub(crate) fn check_width(width: usize) -> Result<(), Error> {
+    if !(1..=8).contains(&width) {
+        return Err(Error::InvalidWidth { width });
+    }
+    Ok(())
+}
+
+/// encodes the given value as an integer of the given width in bytes, or returns `None` if it doesn't fit.
+pub(crate) fn encode_int(
+    value: i128,
+    width: usize,
+    endianness: Endianness,
+    signed: bool,
+) -> Option<Vec<u8>> {
+    let bits = width as u32 * 8;
+    let fits = if signed {
+        value >= -(1i128 << (bits - 1)) && value < (1i128 << (bits - 1))
+    } else {
+        value >= 0 && value < (1i128 << bits)
+    };
+    if !fits {
+        return None;
+    }
+    let bytes = (value as u64).to_le_bytes();
+    let mut bytes = bytes[..width].to_vec();
+    if endianness == Endianness::Big {
+        bytes.reverse();
+    }
+    Some(bytes)
+}
+
+#[test]
+pub fn make_sure_expression_fixups_are_written() {
+    // a header holding the length of the body in words, followed by the body.
+    let mut buf = IndexRefBuf::from_vec(vec![0, 0, 1, 2, 3, 4]);
+    let site = buf.create_index_ref(0);
+    let body = buf.create_range_ref(2..6);
+    let words = (Expr::from(body.end()) - body.start()) / 2;
+    buf.add_fixup(Fixup::absolute(site, words, 2, Endianness::Big));
+    buf.insert_slice(6, &[5, 6]);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..2], &[0, 3]);
+}
+
+#[test]
+pub fn make_sure_fixups_follow_their_targets() {
+    // a `jmp rel8` over a single `nop`, followed by a `mov eax, imm32` whose immediate is the absolute index of the nop.
+    let mut buf = IndexRefBuf::from_vec(vec![0xeb, 0x00, 0x90, 0xb8, 0, 0, 0, 0]);
+    let jmp_site = buf.create_index_ref(1);
+    let nop = buf.create_index_ref(2);
+    let jmp_target = buf.create_index_ref(3);
+    let mov_site = buf.create_index_ref(4);
+    buf.add_fixup(Fixup::relative(jmp_site, jmp_target, 1, Endianness::Little).with_addend(-1));
+    buf.add_fixup(Fixup::absolute(mov_site, nop, 4, Endianness::Big));
+
+    buf.insert_slice(0, &[0x90; 3]);
+    buf.insert_slice(5, &[0x90; 2]);
+    buf.resolve_fixups().unwrap();
+    assert_eq!(
+        &buf[..],
+        &[0x90, 0x90, 0x90, 0xeb, 0x03, 0x90, 0x90, 0x90, 0xb8, 0, 0, 0, 7]
+    );
+
+    // pushing the target out of the range of a rel8 is reported, and leaves the patch site unchanged.
+    buf.insert_slice(5, &[0x90; 200]);
+    let errors = buf.resolve_fixups().unwrap_err();
+    assert_eq!(
+        errors,
+        vec![Error::FixupOverflow {
+            site: 4,
+            value: 203,
+            width: 1
+        }]
+    );
+    assert_eq!(buf[4], 0x03);
+}
+
+#[test]
+pub fn make_sure_invalid_widths_are_rejected_by_constructors() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 4]);
+    let site = buf.create_index_ref(0);
+    assert_eq!(
+        Fixup::try_new(site, site, 0, Endianness::Little, FixupKind::Absolute),
+        Err(Error::InvalidWidth { width: 0 })
+    );
+    assert_eq!(
+        Fixup::try_new(site, site, 9, Endianness::Little, FixupKind::Relative),
+        Err(Error::InvalidWidth { width: 9 })
+    );
+}
@@ -0,0 +1,83 @@
+//! implementations of the `std::io` traits, which allow using buffers with existing serializers.
+
+use crate::{IndexRefBuf, IndexRefCursor};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// appends written bytes to the end of the buffer, like `extend_from_slice`.
+impl Write for IndexRefBuf<u8> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// inserts written bytes at the cursor, and advances the cursor past them, moving all index refs after the cursor.
+impl Write for IndexRefCursor<'_, u8> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.emit(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// reads bytes from the cursor, and advances the cursor past them, without modifying the buffer.
+impl Read for IndexRefCursor<'_, u8> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let position = self.position();
+        let available = &self.buf()[position..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.seek_to(position + len);
+        Ok(len)
+    }
+}
+
+/// moves the cursor. unlike `std::io::Cursor`, the cursor can't be moved past the end of the buffer.
+impl Seek for IndexRefCursor<'_, u8> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (0, offset as i128),
+            SeekFrom::End(offset) => (self.buf().len(), offset as i128),
+            SeekFrom::Current(offset) => (self.position(), offset as i128),
+        };
+        let index = usize::try_from(base as i128 + offset)
+            .ok()
+            .filter(|&index| index <= self.buf().len())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "seek to a position outside of the buffer",
+                )
+            })?;
+        self.seek_to(index);
+        Ok(index as u64)
+    }
+}
+
+#[test]
+pub fn make_sure_io_traits_keep_refs_updated() {
+    let mut buf = IndexRefBuf::from_vec(b"<>".to_vec());
+    let close = buf.create_index_ref(1);
+    write!(buf, "{}", 42).unwrap();
+    assert_eq!(&buf[..], b"<>42");
+
+    let mut cursor = buf.cursor_at(1);
+    cursor.write_all(b"tag").unwrap();
+    let mut rest = Vec::new();
+    cursor.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b">42");
+    assert_eq!(cursor.seek(SeekFrom::Current(-3)).unwrap(), 4);
+    let mut byte = [0];
+    cursor.read_exact(&mut byte).unwrap();
+    assert_eq!(&byte, b">");
+    assert!(cursor.seek(SeekFrom::End(1)).is_err());
+    drop(cursor);
+
+    assert_eq!(&buf[..], b"<tag>42");
+    assert_eq!(buf.read_index_ref(close), 4);
+}
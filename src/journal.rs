@@ -0,0 +1,258 @@
+//! an optional journal of edits, which maps offsets from a checkpoint to the current buffer and back, without creating
+//! index refs in advance.
+
+use crate::{ref_tree::RefKey, unwrap_or_panic, Error, Gravity, IndexRefBuf, RemovalPolicy};
+
+/// a single recorded edit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum JournalEdit {
+    /// a range was replaced, which also covers plain insertions and removals.
+    Splice {
+        start: usize,
+        end: usize,
+        replacement_len: usize,
+        policy: RemovalPolicy,
+    },
+    /// content was appended without moving any index refs other than ones with `Gravity::End`.
+    Append { old_len: usize, len: usize },
+}
+impl JournalEdit {
+    /// maps an offset from before the edit to after it, following the same rules as index refs.
+    fn map_forward(self, index: usize, gravity: Gravity) -> Option<usize> {
+        let key = RefKey::new(index, gravity);
+        match self {
+            JournalEdit::Splice {
+                start,
+                end,
+                replacement_len,
+                policy,
+            } => {
+                if start == end {
+                    return Some(if key >= RefKey::new(start, Gravity::Right) {
+                        index + replacement_len
+                    } else {
+                        index
+                    });
+                }
+                let (affected_start, affected_end) = if replacement_len == 0 {
+                    (
+                        RefKey::new(start, Gravity::Right),
+                        RefKey::new(end, Gravity::Right),
+                    )
+                } else {
+                    (
+                        RefKey::new(start + 1, Gravity::Left),
+                        RefKey::new(end, Gravity::Left),
+                    )
+                };
+                if key < affected_start {
+                    Some(index)
+                } else if key >= affected_end {
+                    Some(index + replacement_len - (end - start))
+                } else {
+                    match policy {
+                        RemovalPolicy::ClampToStart => Some(start),
+                        RemovalPolicy::ClampToEnd => Some(start + replacement_len),
+                        RemovalPolicy::Invalidate => None,
+                    }
+                }
+            }
+            JournalEdit::Append { old_len, len } => {
+                Some(if key >= RefKey::new(old_len, Gravity::End) {
+                    index + len
+                } else {
+                    index
+                })
+            }
+        }
+    }
+    /// maps an offset from after the edit to before it. offsets which are attached to inserted content, according to
+    /// their gravity, did not exist before the edit.
+    fn map_backward(self, index: usize, gravity: Gravity) -> Option<usize> {
+        match self {
+            JournalEdit::Splice {
+                start,
+                end,
+                replacement_len,
+                ..
+            } => {
+                let replacement_end = start + replacement_len;
+                if start == end {
+                    // a plain insertion, where a left gravity offset is attached to the element before it.
+                    let attached = if gravity == Gravity::Left {
+                        index.checked_sub(1)
+                    } else {
+                        Some(index)
+                    };
+                    if attached.is_some_and(|attached| (start..replacement_end).contains(&attached))
+                    {
+                        None
+                    } else if index <= start {
+                        Some(index)
+                    } else {
+                        Some(index - replacement_len)
+                    }
+                } else if replacement_len == 0 && index == start {
+                    // a plain removal, where the offset was either at the start or at the end of the removed range.
+                    Some(if gravity == Gravity::Left { start } else { end })
+                } else if index <= start {
+                    Some(index)
+                } else if index < replacement_end {
+                    None
+                } else {
+                    Some(index - replacement_len + (end - start))
+                }
+            }
+            JournalEdit::Append { old_len, len } => {
+                if len == 0 || index < old_len {
+                    Some(index)
+                } else if index == old_len {
+                    (gravity != Gravity::End).then_some(old_len)
+                } else if index == old_len + len && gravity == Gravity::End {
+                    Some(old_len)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// the edits recorded since the last checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Journal {
+    checkpoint_len: usize,
+    edits: Vec<JournalEdit>,
+}
+
+impl<T> IndexRefBuf<T> {
+    /// starts recording edits, and sets a checkpoint at the current state of the buffer. if edits are already being
+    /// recorded, they are discarded, and the checkpoint is moved to the current state.
+    pub fn checkpoint(&mut self) {
+        self.journal = Some(Journal {
+            checkpoint_len: self.len(),
+            edits: Vec::new(),
+        });
+    }
+    /// stops recording edits, and discards the recorded edits.
+    pub fn stop_journal(&mut self) {
+        self.journal = None;
+    }
+    /// checks if edits are being recorded.
+    pub fn is_journaling(&self) -> bool {
+        self.journal.is_some()
+    }
+    /// maps an offset in the buffer at the checkpoint to the current buffer, moving it like an index ref with the given
+    /// gravity would have moved. returns `None` if an index ref at the offset would have been invalidated.
+    /// panics if edits are not being recorded, or if the offset is out of bound of the buffer at the checkpoint.
+    pub fn map_old_offset(&self, old: usize, gravity: Gravity) -> Option<usize> {
+        unwrap_or_panic(self.try_map_old_offset(old, gravity))
+    }
+    /// like `map_old_offset`, but returns an error instead of panicking.
+    pub fn try_map_old_offset(&self, old: usize, gravity: Gravity) -> Result<Option<usize>, Error> {
+        let journal = self.journal.as_ref().ok_or(Error::NotJournaling)?;
+        if old > journal.checkpoint_len {
+            return Err(Error::OutOfBounds {
+                index: old,
+                len: journal.checkpoint_len,
+            });
+        }
+        Ok(journal
+            .edits
+            .iter()
+            .try_fold(old, |index, edit| edit.map_forward(index, gravity)))
+    }
+    /// maps an offset in the current buffer back to the buffer at the checkpoint, which is the reverse of
+    /// `map_old_offset`. returns `None` if the offset is attached to content which was inserted after the checkpoint,
+    /// where an offset with `Gravity::Left` is attached to the element before it, and any other offset is attached to the
+    /// element at it.
+    /// panics if edits are not being recorded, or if the offset is out of bound of the buffer.
+    pub fn map_new_offset(&self, new: usize, gravity: Gravity) -> Option<usize> {
+        unwrap_or_panic(self.try_map_new_offset(new, gravity))
+    }
+    /// like `map_new_offset`, but returns an error instead of panicking.
+    pub fn try_map_new_offset(&self, new: usize, gravity: Gravity) -> Result<Option<usize>, Error> {
+        let journal = self.journal.as_ref().ok_or(Error::NotJournaling)?;
+        self.check_insertion_index(new)?;
+        Ok(journal
+            .edits
+            .iter()
+            .rev()
+            .try_fold(new, |index, edit| edit.map_backward(index, gravity)))
+    }
+    /// records the given edit, if edits are being recorded.
+    pub(crate) fn record_edit(&mut self, edit: JournalEdit) {
+        if let Some(journal) = &mut self.journal {
+            journal.edits.push(edit);
+        }
+    }
+}
+
+#[test]
+pub fn make_sure_old_offsets_map_like_index_refs() {
+    let gravities = [Gravity::Left, Gravity::Right, Gravity::End];
+    let mut rng_state = 7u64;
+    let mut next = |bound: usize| {
+        rng_state = rng_state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (rng_state >> 33) as usize % bound
+    };
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 16]);
+    buf.checkpoint();
+    let refs: Vec<_> = (0..=16)
+        .flat_map(|index| gravities.map(|gravity| (index, gravity)))
+        .map(|(index, gravity)| {
+            (
+                index,
+                gravity,
+                buf.create_index_ref_with_gravity(index, gravity),
+            )
+        })
+        .collect();
+    for _ in 0..200 {
+        let policy = [
+            RemovalPolicy::ClampToStart,
+            RemovalPolicy::ClampToEnd,
+            RemovalPolicy::Invalidate,
+        ][next(3)];
+        buf.set_removal_policy(policy);
+        let start = next(buf.len() + 1);
+        let end = start + next(buf.len() - start + 1).min(3);
+        match next(3) {
+            0 => {
+                buf.splice(start..end, vec![0; next(4)]);
+            }
+            1 => buf.extend_from_slice(&vec![0; next(3)]),
+            _ => {
+                buf.drain(start..end);
+            }
+        }
+        for &(index, gravity, index_ref) in &refs {
+            assert_eq!(
+                buf.map_old_offset(index, gravity),
+                buf.get_index_ref(index_ref)
+            );
+        }
+    }
+}
+
+#[test]
+pub fn make_sure_new_offsets_map_back_to_old_offsets() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 4]);
+    buf.checkpoint();
+    buf.insert_slice(2, &[1, 1]);
+    buf.drain(0..1);
+    buf.push(2);
+    // [0, 1, 1, 0, 0, 2], where 0 was at 1, the inserted content is at 1..3, and 2 was appended.
+    assert_eq!(buf.map_new_offset(0, Gravity::Right), Some(1));
+    assert_eq!(buf.map_new_offset(1, Gravity::Left), Some(2));
+    assert_eq!(buf.map_new_offset(1, Gravity::Right), None);
+    assert_eq!(buf.map_new_offset(3, Gravity::Left), None);
+    assert_eq!(buf.map_new_offset(3, Gravity::Right), Some(2));
+    assert_eq!(buf.map_new_offset(5, Gravity::Right), Some(4));
+    assert_eq!(buf.map_new_offset(6, Gravity::End), Some(4));
+    assert_eq!(buf.map_new_offset(6, Gravity::Right), None);
+    assert_eq!(buf.map_old_offset(0, Gravity::Left), Some(0));
+    assert_eq!(buf.map_old_offset(0, Gravity::Right), None);
+}
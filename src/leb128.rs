@@ -0,0 +1,81 @@
+//! encoding and decoding of LEB128 variable length integers.
+
+/// encodes the given value as an unsigned LEB128 integer, using the minimal number of bytes.
+pub(crate) fn encode_uleb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// encodes the given value as a signed LEB128 integer, using the minimal number of bytes.
+pub(crate) fn encode_sleb128(mut value: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let sign_bit = byte & 0x40 != 0;
+        if (value == 0 && !sign_bit) || (value == -1 && sign_bit) {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// pads the given minimal LEB128 encoding to the given width by adding redundant bytes, or returns `None` if it is
+/// longer than the width. the redundant bytes extend the sign of the value for negative signed values.
+pub(crate) fn pad_leb128(mut bytes: Vec<u8>, width: usize, negative: bool) -> Option<Vec<u8>> {
+    if bytes.len() > width {
+        return None;
+    }
+    if bytes.len() < width {
+        *bytes.last_mut().unwrap() |= 0x80;
+        let pad = if negative { 0x7f } else { 0 };
+        bytes.resize(width - 1, pad | 0x80);
+        bytes.push(pad);
+    }
+    Some(bytes)
+}
+
+/// decodes an unsigned LEB128 integer from the start of the given bytes, returning its value and its length, or `None`
+/// if it is truncated or doesn't fit in 64 bits.
+pub(crate) fn decode_uleb128(bytes: &[u8]) -> Option<(u64, usize)> {
+    let (value, len) = decode_leb128(bytes)?;
+    Some((u64::try_from(value).ok()?, len))
+}
+
+/// decodes a signed LEB128 integer from the start of the given bytes, returning its value and its length, or `None` if
+/// it is truncated or doesn't fit in 64 bits.
+pub(crate) fn decode_sleb128(bytes: &[u8]) -> Option<(i64, usize)> {
+    let (value, len) = decode_leb128(bytes)?;
+    let value = if bytes[len - 1] & 0x40 != 0 {
+        value | (-1i128 << (7 * len))
+    } else {
+        value
+    };
+    Some((i64::try_from(value).ok()?, len))
+}
+
+/// the maximum length of a decoded LEB128 integer, which is more than any padded 64 bit integer needs, and still fits in
+/// 126 bits.
+pub(crate) const MAX_LEB128_LEN: usize = 18;
+
+/// decodes the bits of a LEB128 integer without extending its sign, returning its value and its length, or `None` if it
+/// is truncated or longer than `MAX_LEB128_LEN` bytes.
+fn decode_leb128(bytes: &[u8]) -> Option<(i128, usize)> {
+    let mut value = 0i128;
+    for (i, &byte) in bytes.iter().enumerate().take(MAX_LEB128_LEN) {
+        value |= ((byte & 0x7f) as i128) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
@@ -0,0 +1,281 @@
+//! length-prefixed regions, whose length header is recomputed whenever the buffer is finalized.
+
+use crate::{
+    fixup::{check_width, encode_int},
+    leb128::encode_uleb128,
+    tlv::encode_der_length,
+    unwrap_or_panic, Endianness, Error, Gravity, IndexRangeRef, IndexRefBuf,
+};
+use std::ops::Range;
+
+/// the encoding of the header of a length field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LengthEncoding {
+    /// an unsigned integer with the given width in bytes, which must be between 1 and 8.
+    Fixed {
+        width: usize,
+        endianness: Endianness,
+    },
+    /// a minimal unsigned LEB128 integer, whose width depends on the length.
+    Uleb128,
+    /// a DER length, which uses the short form for lengths below 128, and the minimal long form otherwise.
+    Der,
+}
+impl LengthEncoding {
+    /// encodes the given length, or returns `None` if it doesn't fit.
+    fn encode(self, len: usize) -> Option<Vec<u8>> {
+        match self {
+            LengthEncoding::Fixed { width, endianness } => {
+                encode_int(len as i128, width, endianness, false)
+            }
+            LengthEncoding::Uleb128 => Some(encode_uleb128(len as u64)),
+            LengthEncoding::Der => Some(encode_der_length(len)),
+        }
+    }
+}
+
+/// a length-prefixed region of a buffer, made of a header which holds the length of the body, and the body itself.
+///
+/// content inserted at the start of the body, right after the header, and content inserted at the end of the body,
+/// becomes part of the body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct LengthField {
+    header: IndexRangeRef,
+    body: IndexRangeRef,
+}
+impl LengthField {
+    /// the range ref of the header.
+    pub fn header(&self) -> IndexRangeRef {
+        self.header
+    }
+    /// the range ref of the body.
+    pub fn body(&self) -> IndexRangeRef {
+        self.body
+    }
+}
+
+/// a length field registered in a buffer, together with the encoding of its header.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct LengthFieldEntry {
+    field: LengthField,
+    encoding: LengthEncoding,
+}
+impl LengthFieldEntry {
+    /// the same length field, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            field: LengthField {
+                header: self.field.header.rebind(old_buf_id, buf_id),
+                body: self.field.body.rebind(old_buf_id, buf_id),
+            },
+            encoding: self.encoding,
+        }
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// creates a length field whose body is the given range, by inserting a header right before it.
+    /// the header is written immediately, and is then kept correct by `update_length_fields` and `finalize`.
+    pub fn create_length_field(
+        &mut self,
+        body: Range<usize>,
+        encoding: LengthEncoding,
+    ) -> LengthField {
+        unwrap_or_panic(self.try_create_length_field(body, encoding))
+    }
+    /// like `create_length_field`, but returns an error instead of panicking.
+    pub fn try_create_length_field(
+        &mut self,
+        body: Range<usize>,
+        encoding: LengthEncoding,
+    ) -> Result<LengthField, Error> {
+        self.try_range_indices(&body)?;
+        if let LengthEncoding::Fixed { width, .. } = encoding {
+            check_width(width)?;
+        }
+        let header_bytes = encoding.encode(body.len()).ok_or(Error::FixupOverflow {
+            site: body.start,
+            value: body.len() as i128,
+            width: header_width(encoding),
+        })?;
+        self.try_insert_slice(body.start, &header_bytes)?;
+        let header_len = header_bytes.len();
+        let header = self.try_create_range_ref_with_gravity(
+            body.start..body.start + header_len,
+            Gravity::Right,
+            Gravity::Left,
+        )?;
+        let body = self.try_create_range_ref(body.start + header_len..body.end + header_len)?;
+        let field = LengthField { header, body };
+        self.length_fields
+            .push(LengthFieldEntry { field, encoding });
+        Ok(field)
+    }
+    /// inserts the given bytes at the end of the body of the given length field.
+    /// unlike inserting at the end index of the body, which would also grow the nested length fields whose body ends at
+    /// that index, the bytes only become part of the given length field and of the length fields that enclose it.
+    pub fn append_to_length_field(&mut self, field: LengthField, bytes: &[u8]) {
+        unwrap_or_panic(self.try_append_to_length_field(field, bytes))
+    }
+    /// like `append_to_length_field`, but returns an error instead of panicking.
+    pub fn try_append_to_length_field(
+        &mut self,
+        field: LengthField,
+        bytes: &[u8],
+    ) -> Result<(), Error> {
+        let body = self.try_read_range_ref(field.body)?;
+        self.try_insert_slice(body.end, bytes)?;
+        // nested bodies start after the start of this body, while enclosing bodies start before it.
+        let nested_ends: Vec<_> = self
+            .length_fields
+            .iter()
+            .map(|entry| entry.field.body)
+            .filter(|nested| {
+                self.get_range_ref(*nested).is_some_and(|nested| {
+                    nested.start > body.start
+                        && nested.start <= body.end
+                        && nested.end == body.end + bytes.len()
+                })
+            })
+            .map(|nested| nested.end())
+            .collect();
+        for nested_end in nested_ends {
+            self.move_index_ref(nested_end, body.end)?;
+        }
+        Ok(())
+    }
+    /// rewrites the header of every length field according to the current length of its body.
+    /// headers whose width depends on the length may be resized, which changes the length of enclosing length fields, so
+    /// the headers are updated from the innermost length field outwards.
+    pub fn update_length_fields(&mut self) -> Result<(), Vec<Error>> {
+        self.composite_edit(|buf| {
+            while buf.resize_length_fields() {}
+            let errors = buf.write_length_fields();
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        })
+    }
+    /// resizes the headers of length fields whose encoded length no longer has the width of their header. returns
+    /// whether any header was resized.
+    pub(crate) fn resize_length_fields(&mut self) -> bool {
+        let mut resized = false;
+        for entry_index in self.length_fields_by_body_len() {
+            let entry = &self.length_fields[entry_index];
+            let (Ok(header), Ok(body)) = (
+                self.try_read_range_ref(entry.field.header),
+                self.try_read_range_ref(entry.field.body),
+            ) else {
+                continue;
+            };
+            let Some(header_bytes) = entry.encoding.encode(body.len()) else {
+                continue;
+            };
+            if header_bytes.len() != header.len() {
+                self.splice(header, header_bytes);
+                resized = true;
+            }
+        }
+        resized
+    }
+    /// writes the headers of all length fields, returning an error for every header that could not be written.
+    pub(crate) fn write_length_fields(&mut self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for entry_index in self.length_fields_by_body_len() {
+            if let Err(err) = self.write_length_field(entry_index) {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+    /// writes the header of a single length field.
+    fn write_length_field(&mut self, entry_index: usize) -> Result<(), Error> {
+        let entry = &self.length_fields[entry_index];
+        let header = self.try_read_range_ref(entry.field.header)?;
+        let body = self.try_read_range_ref(entry.field.body)?;
+        let header_bytes = entry
+            .encoding
+            .encode(body.len())
+            .ok_or(Error::FixupOverflow {
+                site: header.start,
+                value: body.len() as i128,
+                width: header.len(),
+            })?;
+        if header_bytes.len() != header.len() {
+            self.splice(header, header_bytes);
+        } else {
+            self.buf[header].copy_from_slice(&header_bytes);
+        }
+        Ok(())
+    }
+    /// the indices of all length fields, ordered by the length of their body, so that nested length fields come before
+    /// the length fields that enclose them.
+    fn length_fields_by_body_len(&self) -> Vec<usize> {
+        let mut entry_indices: Vec<usize> = (0..self.length_fields.len()).collect();
+        entry_indices.sort_by_key(|&entry_index| {
+            self.get_range_ref(self.length_fields[entry_index].field.body)
+                .map_or(0, |body| body.len())
+        });
+        entry_indices
+    }
+}
+
+/// the width of the header of the given encoding, used for reporting overflows.
+fn header_width(encoding: LengthEncoding) -> usize {
+    match encoding {
+        LengthEncoding::Fixed { width, .. } => width,
+        LengthEncoding::Uleb128 => 10,
+        LengthEncoding::Der => 1 + std::mem::size_of::<usize>(),
+    }
+}
+
+#[test]
+pub fn make_sure_nested_length_fields_are_kept_correct() {
+    let mut buf = IndexRefBuf::new();
+    let outer = buf.create_length_field(0..0, LengthEncoding::Uleb128);
+    let inner_start = buf.read_range_ref(outer.body()).start;
+    let inner = buf.create_length_field(
+        inner_start..inner_start,
+        LengthEncoding::Fixed {
+            width: 2,
+            endianness: Endianness::Big,
+        },
+    );
+    assert_eq!(&buf[..], &[0, 0, 0]);
+    buf.update_length_fields().unwrap();
+    assert_eq!(&buf[..], &[2, 0, 0]);
+
+    let inner_body = buf.read_range_ref(inner.body());
+    buf.insert_slice(inner_body.start, &[0xaa; 200]);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..5], &[0xca, 0x01, 0, 200, 0xaa]);
+    assert_eq!(buf.read_range_ref(outer.body()), 2..204);
+
+    buf.drain(5..104);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..4], &[103, 0, 101, 0xaa]);
+    assert_eq!(buf.len(), 104);
+}
+
+#[test]
+pub fn make_sure_length_fields_survive_edits_around_them() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xaa; 4]);
+    let wide = LengthEncoding::Fixed {
+        width: 9,
+        endianness: Endianness::Little,
+    };
+    assert_eq!(
+        buf.try_create_length_field(0..2, wide),
+        Err(Error::InvalidWidth { width: 9 })
+    );
+    assert_eq!(buf.len(), 4);
+
+    let field = buf.create_length_field(1..3, LengthEncoding::Uleb128);
+    buf.remove(4);
+    buf.remove(0);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..], &[2, 0xaa, 0xaa]);
+    assert_eq!(buf.read_range_ref(field.body()), 1..3);
+}
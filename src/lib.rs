@@ -1,122 +1,481 @@
-use std::ops::{Deref, RangeBounds};
+mod address;
+mod align;
+mod checksum;
+mod cursor;
+mod error;
+mod expr;
+mod finalize;
+mod fixup;
+mod io;
+mod journal;
+mod leb128;
+mod length_field;
+mod placeholder;
+mod ref_tree;
+mod relax;
+mod section;
+mod tlv;
+mod typed;
+mod varint;
+
+use address::Segment;
+use align::Alignment;
+pub use checksum::{Checksum, ChecksumAlgorithm};
+pub use cursor::IndexRefCursor;
+pub use error::Error;
+pub use expr::{Expr, Symbol};
+pub use fixup::{Endianness, Fixup, FixupKind};
+use journal::{Journal, JournalEdit};
+use length_field::LengthFieldEntry;
+pub use length_field::{LengthEncoding, LengthField};
+pub use placeholder::PlaceholderRef;
+use ref_tree::{RefKey, RefTree};
+pub use relax::RelaxVariant;
+use relax::Relaxable;
+pub use section::{Layout, Section, SectionFixup, SectionRef, SectionedBuf};
+use std::{
+    hash::{Hash, Hasher},
+    ops::{Deref, Range, RangeBounds},
+    sync::atomic::{AtomicU64, Ordering},
+};
+pub use tlv::TlvElement;
+pub use typed::{BufIndex, ByteRepr};
+pub use varint::{VarintEncoding, VarintField};
+
+/// the id that will be given to the next created buffer.
+static NEXT_BUF_ID: AtomicU64 = AtomicU64::new(0);
+
+/// allocates a unique buffer id.
+/// the buffer id which index refs are moved to when comparing buffers, which is never allocated in practice.
+const UNBOUND_BUF_ID: u64 = u64::MAX;
+
+fn alloc_buf_id() -> u64 {
+    NEXT_BUF_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// unwraps the result of a fallible operation, panicking with the message of the error if it failed.
+#[track_caller]
+fn unwrap_or_panic<R>(result: Result<R, Error>) -> R {
+    result.unwrap_or_else(|err| panic!("{}", err))
+}
+
+/// decides what happens to index refs which point into a range of the buffer that was removed or replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RemovalPolicy {
+    /// move the index ref to the start of the removed range.
+    ClampToStart,
+    /// move the index ref to the end of the removed range, which is the end of the replacement content in case of a splice.
+    /// for plain removals, this is the same as `ClampToStart`.
+    ClampToEnd,
+    /// invalidate the index ref, so that it no longer resolves to any index. this is the default, so that index refs never
+    /// silently resolve to a neighbour of their deleted target.
+    #[default]
+    Invalidate,
+}
+
+/// decides where an index ref goes when content is inserted exactly at its index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Gravity {
+    /// the index ref sticks to the element before it, so it stays before content which is inserted at its index.
+    Left,
+    /// the index ref sticks to the element at its index, so it moves after content which is inserted at its index.
+    #[default]
+    Right,
+    /// like `Right`, but when the index ref points to the end of the buffer it also follows `push`, `extend_from_slice`
+    /// and `append`.
+    End,
+}
 
 /// a buffer which can have index references.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct IndexRefBuf {
-    buf: Vec<u8>,
-    references: Vec<usize>,
+///
+/// the buffer is generic over its element type, and defaults to a byte buffer.
+///
+/// every buffer has a unique id, which is stored in the index refs that it creates, so that using an index ref with a
+/// buffer other than the one that created it is detected. cloning a buffer gives the clone a new id, since the clone may
+/// diverge from the original.
+#[derive(Debug)]
+pub struct IndexRefBuf<T = u8> {
+    id: u64,
+    buf: Vec<T>,
+    references: Vec<RefEntry>,
+    ref_tree: RefTree,
+    free_ref_slots: Vec<usize>,
+    removal_policy: RemovalPolicy,
+    invalidated_refs: Vec<IndexRef>,
+    /// the number of composite operations which are currently running, whose edits all add to `invalidated_refs`.
+    composite_depth: usize,
+    fixups: Vec<Fixup>,
+    relaxables: Vec<Relaxable>,
+    symbols: Vec<Option<Expr>>,
+    length_fields: Vec<LengthFieldEntry>,
+    checksums: Vec<Checksum>,
+    alignments: Vec<Alignment<T>>,
+    /// whether the padding of alignment directives is currently being resized, which must not trigger resizing again.
+    realigning: bool,
+    load_base: u64,
+    segments: Vec<Segment>,
+    journal: Option<Journal>,
 }
-impl IndexRefBuf {
+impl<T> IndexRefBuf<T> {
     /// creates a new empty buffer.
     pub fn new() -> Self {
-        Self {
-            buf: Vec::new(),
-            references: Vec::new(),
-        }
+        Self::from_vec(Vec::new())
     }
     /// creates a new buffer with the given content.
-    pub fn from_vec(vec: Vec<u8>) -> Self {
+    pub fn from_vec(vec: Vec<T>) -> Self {
         Self {
+            id: alloc_buf_id(),
             buf: vec,
             references: Vec::new(),
+            ref_tree: RefTree::new(),
+            free_ref_slots: Vec::new(),
+            removal_policy: RemovalPolicy::default(),
+            invalidated_refs: Vec::new(),
+            composite_depth: 0,
+            fixups: Vec::new(),
+            relaxables: Vec::new(),
+            symbols: Vec::new(),
+            length_fields: Vec::new(),
+            checksums: Vec::new(),
+            alignments: Vec::new(),
+            realigning: false,
+            load_base: 0,
+            segments: Vec::new(),
+            journal: None,
         }
     }
-    /// creates an index reference to the given index in the buffer.
+    /// creates an index reference to the given index in the buffer, with the default `Gravity::Right`.
     pub fn create_index_ref(&mut self, index: usize) -> IndexRef {
-        if index > self.len() {
-            panic!(
-                "index {} for creating an index ref is out of bound of buffer with length {}",
-                index,
-                self.len()
-            );
+        self.create_index_ref_with_gravity(index, Gravity::default())
+    }
+    /// creates an index reference to the given index in the buffer, with the given gravity.
+    pub fn create_index_ref_with_gravity(&mut self, index: usize, gravity: Gravity) -> IndexRef {
+        unwrap_or_panic(self.try_create_index_ref_with_gravity(index, gravity))
+    }
+    /// like `create_index_ref`, but returns an error instead of panicking.
+    pub fn try_create_index_ref(&mut self, index: usize) -> Result<IndexRef, Error> {
+        self.try_create_index_ref_with_gravity(index, Gravity::default())
+    }
+    /// like `create_index_ref_with_gravity`, but returns an error instead of panicking.
+    pub fn try_create_index_ref_with_gravity(
+        &mut self,
+        index: usize,
+        gravity: Gravity,
+    ) -> Result<IndexRef, Error> {
+        self.check_insertion_index(index)?;
+        let ref_index = match self.free_ref_slots.pop() {
+            Some(ref_index) => {
+                let entry = &mut self.references[ref_index];
+                entry.released = false;
+                entry.range_partner = None;
+                ref_index
+            }
+            None => {
+                self.references.push(RefEntry {
+                    generation: 0,
+                    released: false,
+                    range_partner: None,
+                });
+                self.references.len() - 1
+            }
+        };
+        self.ref_tree.insert(ref_index, index, gravity);
+        Ok(IndexRef {
+            buf_id: self.id,
+            ref_index,
+            generation: self.references[ref_index].generation,
+        })
+    }
+    /// releases the given index ref, so that its slot can be reused by new index refs.
+    /// using the released index ref afterwards is detected, and never reads the index of the index ref that reused its
+    /// slot.
+    /// panics if the index ref was already released, or if it belongs to a different buffer.
+    pub fn release_index_ref(&mut self, index_ref: IndexRef) {
+        unwrap_or_panic(self.try_release_index_ref(index_ref))
+    }
+    /// like `release_index_ref`, but returns an error instead of panicking.
+    pub fn try_release_index_ref(&mut self, index_ref: IndexRef) -> Result<(), Error> {
+        self.check_index_ref(index_ref)?;
+        self.ref_tree.remove(index_ref.ref_index);
+        let entry = &mut self.references[index_ref.ref_index];
+        entry.released = true;
+        entry.generation = entry.generation.wrapping_add(1);
+        if let Some(partner) = entry.range_partner.take() {
+            self.references[partner].range_partner = None;
         }
-        let ref_index = self.references.len();
-        self.references.push(index);
-        IndexRef { ref_index }
+        self.free_ref_slots.push(index_ref.ref_index);
+        Ok(())
     }
     /// reads the index of the given index ref.
+    /// panics if the target of the index ref was deleted, if the index ref was released, or if it belongs to a different
+    /// buffer.
     pub fn read_index_ref(&self, index_ref: IndexRef) -> usize {
-        self.references[index_ref.ref_index]
+        unwrap_or_panic(self.try_read_index_ref(index_ref))
+    }
+    /// like `read_index_ref`, but returns an error instead of panicking.
+    pub fn try_read_index_ref(&self, index_ref: IndexRef) -> Result<usize, Error> {
+        self.check_index_ref(index_ref)?;
+        self.ref_tree
+            .index(index_ref.ref_index)
+            .ok_or(Error::DeletedTarget)
+    }
+    /// reads the index of the given index ref, or returns `None` if its target was deleted, if it was released, or if it
+    /// belongs to a different buffer.
+    pub fn get_index_ref(&self, index_ref: IndexRef) -> Option<usize> {
+        self.try_read_index_ref(index_ref).ok()
+    }
+    /// the gravity of the given index ref.
+    /// panics if the index ref was released, or if it belongs to a different buffer.
+    pub fn index_ref_gravity(&self, index_ref: IndexRef) -> Gravity {
+        unwrap_or_panic(self.try_index_ref_gravity(index_ref))
+    }
+    /// like `index_ref_gravity`, but returns an error instead of panicking.
+    pub fn try_index_ref_gravity(&self, index_ref: IndexRef) -> Result<Gravity, Error> {
+        self.check_index_ref(index_ref)?;
+        Ok(self.ref_tree.gravity(index_ref.ref_index))
+    }
+    /// checks if the target of the given index ref is still alive.
+    pub fn is_index_ref_alive(&self, index_ref: IndexRef) -> bool {
+        self.get_index_ref(index_ref).is_some()
+    }
+    /// creates a range reference to the given range in the buffer.
+    /// the start of the range has `Gravity::Left` and its end has `Gravity::Right`, so content inserted exactly at either
+    /// boundary of the range becomes part of it.
+    ///
+    /// the boundaries of a range ref are exclusive, so removing the elements next to the range never invalidates it.
+    /// a boundary inside of a removed range is clamped to its start, and the removal policy only applies if the removed
+    /// range strictly contains the whole range ref, including the elements on both sides of it.
+    pub fn create_range_ref(&mut self, range: Range<usize>) -> IndexRangeRef {
+        self.create_range_ref_with_gravity(range, Gravity::Left, Gravity::Right)
+    }
+    /// creates a range reference to the given range in the buffer, with the given gravities for its boundaries.
+    /// for example, using `Gravity::Right` for the start and `Gravity::Left` for the end makes content inserted at either
+    /// boundary of the range stay outside of it.
+    pub fn create_range_ref_with_gravity(
+        &mut self,
+        range: Range<usize>,
+        start_gravity: Gravity,
+        end_gravity: Gravity,
+    ) -> IndexRangeRef {
+        unwrap_or_panic(self.try_create_range_ref_with_gravity(range, start_gravity, end_gravity))
+    }
+    /// like `create_range_ref`, but returns an error instead of panicking.
+    pub fn try_create_range_ref(&mut self, range: Range<usize>) -> Result<IndexRangeRef, Error> {
+        self.try_create_range_ref_with_gravity(range, Gravity::Left, Gravity::Right)
+    }
+    /// like `create_range_ref_with_gravity`, but returns an error instead of panicking.
+    pub fn try_create_range_ref_with_gravity(
+        &mut self,
+        range: Range<usize>,
+        start_gravity: Gravity,
+        end_gravity: Gravity,
+    ) -> Result<IndexRangeRef, Error> {
+        self.try_range_indices(&range)?;
+        let start = self.try_create_index_ref_with_gravity(range.start, start_gravity)?;
+        let end = self.try_create_index_ref_with_gravity(range.end, end_gravity)?;
+        self.references[start.ref_index].range_partner = Some(end.ref_index);
+        self.references[end.ref_index].range_partner = Some(start.ref_index);
+        Ok(IndexRangeRef { start, end })
+    }
+    /// releases both boundaries of the given range ref.
+    pub fn release_range_ref(&mut self, range_ref: IndexRangeRef) {
+        unwrap_or_panic(self.try_release_range_ref(range_ref))
+    }
+    /// like `release_range_ref`, but returns an error instead of panicking.
+    pub fn try_release_range_ref(&mut self, range_ref: IndexRangeRef) -> Result<(), Error> {
+        self.check_index_ref(range_ref.start)?;
+        self.check_index_ref(range_ref.end)?;
+        self.try_release_index_ref(range_ref.start)?;
+        self.try_release_index_ref(range_ref.end)
+    }
+    /// reads the range of the given range ref.
+    /// if insertions moved the start of the range past its end, which can only happen to an empty range whose start does
+    /// not have left gravity while its end does, the range is read as an empty range at its start.
+    /// panics if one of the boundaries of the range was deleted or released, or if the range ref belongs to a different
+    /// buffer.
+    pub fn read_range_ref(&self, range_ref: IndexRangeRef) -> Range<usize> {
+        unwrap_or_panic(self.try_read_range_ref(range_ref))
+    }
+    /// like `read_range_ref`, but returns an error instead of panicking.
+    pub fn try_read_range_ref(&self, range_ref: IndexRangeRef) -> Result<Range<usize>, Error> {
+        let start = self.try_read_index_ref(range_ref.start)?;
+        let end = self.try_read_index_ref(range_ref.end)?;
+        Ok(start..end.max(start))
+    }
+    /// reads the range of the given range ref, or returns `None` if one of its boundaries was deleted or released, or if
+    /// it belongs to a different buffer.
+    pub fn get_range_ref(&self, range_ref: IndexRangeRef) -> Option<Range<usize>> {
+        self.try_read_range_ref(range_ref).ok()
+    }
+    /// the current content of the given range ref.
+    pub fn range_ref_slice(&self, range_ref: IndexRangeRef) -> &[T] {
+        unwrap_or_panic(self.try_range_ref_slice(range_ref))
+    }
+    /// like `range_ref_slice`, but returns an error instead of panicking.
+    pub fn try_range_ref_slice(&self, range_ref: IndexRangeRef) -> Result<&[T], Error> {
+        Ok(&self.buf[self.try_read_range_ref(range_ref)?])
+    }
+    /// the index refs that were invalidated by the last operation on the buffer.
+    /// for composite operations which edit the buffer several times, like `finalize`, `relax`, `update_length_fields`
+    /// and `update_alignments`, this lists the index refs invalidated by all of their edits.
+    pub fn invalidated_refs(&self) -> &[IndexRef] {
+        &self.invalidated_refs
+    }
+    /// the policy used for index refs which point into a removed range.
+    pub fn removal_policy(&self) -> RemovalPolicy {
+        self.removal_policy
+    }
+    /// sets the policy used for index refs which point into a removed range.
+    pub fn set_removal_policy(&mut self, policy: RemovalPolicy) {
+        self.removal_policy = policy;
     }
     /// push the given element to the buffer.
-    /// this does not update any of the index refs, even if they point to the end of the buffer.
-    /// if you want to insert to the end of the buffer while updating index refs that point to the end, use one of the insertion
-    /// functions, or the splice function.
-    pub fn push(&mut self, value: u8) {
+    /// this only updates index refs with `Gravity::End` that point to the end of the buffer.
+    /// if you want to insert to the end of the buffer while updating all index refs that point to the end, use one of the
+    /// insertion functions, or the splice function.
+    pub fn push(&mut self, value: T) {
+        let old_len = self.len();
         self.buf.push(value);
+        self.update_end_references(old_len);
     }
     /// extend the buffer using the content of the given slice.
-    /// this does not update any of the index refs, even if they point to the end of the buffer.
-    /// if you want to insert to the end of the buffer while updating index refs that point to the end, use one of the insertion
-    /// functions, or the splice function.
-    pub fn extend_from_slice(&mut self, other: &[u8]) {
+    /// this only updates index refs with `Gravity::End` that point to the end of the buffer.
+    /// if you want to insert to the end of the buffer while updating all index refs that point to the end, use one of the
+    /// insertion functions, or the splice function.
+    pub fn extend_from_slice(&mut self, other: &[T])
+    where
+        T: Clone,
+    {
+        let old_len = self.len();
         self.buf.extend_from_slice(other);
+        self.update_end_references(old_len);
     }
     /// appends the given vector to the buffer.
-    /// this does not update any of the index refs, even if they point to the end of the buffer.
-    /// if you want to insert to the end of the buffer while updating index refs that point to the end, use one of the insertion
-    /// functions, or the splice function.
-    pub fn append(&mut self, other: &mut Vec<u8>) {
-        self.buf.append(other)
+    /// this only updates index refs with `Gravity::End` that point to the end of the buffer.
+    /// if you want to insert to the end of the buffer while updating all index refs that point to the end, use one of the
+    /// insertion functions, or the splice function.
+    pub fn append(&mut self, other: &mut Vec<T>) {
+        let old_len = self.len();
+        self.buf.append(other);
+        self.update_end_references(old_len);
     }
     /// inserts an element into the buffer at the given index.
-    /// this updates all of the index refs that point to or after the given index.
-    pub fn insert(&mut self, index: usize, element: u8) {
+    /// this updates all of the index refs that point after the given index, and the ones that point to it unless they
+    /// have `Gravity::Left`.
+    pub fn insert(&mut self, index: usize, element: T) {
+        unwrap_or_panic(self.try_insert(index, element))
+    }
+    /// like `insert`, but returns an error instead of panicking.
+    pub fn try_insert(&mut self, index: usize, element: T) -> Result<(), Error> {
+        self.check_insertion_index(index)?;
         self.buf.insert(index, element);
-        for reference in &mut self.references {
-            if *reference >= index {
-                *reference += 1;
-            }
-        }
+        self.update_references(index, index, 1);
+        Ok(())
     }
     /// inserts a slice into the buffer at the given index.
-    /// this updates all of the index refs that point to or after the given index.
-    pub fn insert_slice(&mut self, index: usize, elements: &[u8]) {
-        self.buf.splice(index..index, elements.iter().copied());
-        for reference in &mut self.references {
-            if *reference >= index {
-                *reference += elements.len();
-            }
+    /// this updates all of the index refs that point after the given index, and the ones that point to it unless they
+    /// have `Gravity::Left`.
+    pub fn insert_slice(&mut self, index: usize, elements: &[T])
+    where
+        T: Clone,
+    {
+        unwrap_or_panic(self.try_insert_slice(index, elements))
+    }
+    /// like `insert_slice`, but returns an error instead of panicking.
+    pub fn try_insert_slice(&mut self, index: usize, elements: &[T]) -> Result<(), Error>
+    where
+        T: Clone,
+    {
+        self.check_insertion_index(index)?;
+        self.buf.splice(index..index, elements.iter().cloned());
+        self.update_references(index, index, elements.len());
+        Ok(())
+    }
+    /// removes the element at the given index, shifting all elements after it.
+    /// index refs that are attached to the removed element are handled according to the removal policy, and index refs that
+    /// point after it are moved back by one.
+    pub fn remove(&mut self, index: usize) -> T {
+        unwrap_or_panic(self.try_remove(index))
+    }
+    /// like `remove`, but returns an error instead of panicking.
+    pub fn try_remove(&mut self, index: usize) -> Result<T, Error> {
+        if index >= self.len() {
+            return Err(Error::OutOfBounds {
+                index,
+                len: self.len(),
+            });
         }
+        let element = self.buf.remove(index);
+        self.update_references(index, index + 1, 0);
+        Ok(element)
     }
-    /// replaces the given range with the given content.
-    /// this updates all of the index refs that point to or after the given range start index.
-    pub fn splice<R, I, T>(
-        &mut self,
-        range: R,
-        replace_with: I,
-    ) -> std::vec::Splice<'_, I::IntoIter>
+    /// removes the given range from the buffer, and returns the removed elements.
+    /// index refs that are attached to elements in the range are handled according to the removal policy, and index refs
+    /// that point after it are moved back by the length of the range.
+    /// an index ref is attached to the element at its index, or to the element before it if it has `Gravity::Left`.
+    pub fn drain<R>(&mut self, range: R) -> Vec<T>
     where
         R: RangeBounds<usize>,
-        I: IntoIterator<Item = u8, IntoIter = T>,
-        T: Iterator<Item = u8> + ExactSizeIterator,
     {
-        let range_start_index = match range.start_bound() {
-            std::ops::Bound::Included(x) => *x,
-            std::ops::Bound::Excluded(x) => *x + 1,
-            std::ops::Bound::Unbounded => 0,
-        };
-        let range_end_index = match range.end_bound() {
-            std::ops::Bound::Included(x) => *x + 1,
-            std::ops::Bound::Excluded(x) => *x,
-            std::ops::Bound::Unbounded => self.buf.len(),
-        };
-        let replace_with_iter = replace_with.into_iter();
-        let replace_with_len = replace_with_iter.len();
-        let result = self.buf.splice(range, replace_with_iter);
-
-        let range_len = range_end_index - range_start_index;
-        let increase_in_size = replace_with_len
-            .checked_sub(range_len)
-            .expect("index referencable buffers may only grow, shrinking is not allowed");
-        if increase_in_size > 0 {
-            for reference in &mut self.references {
-                if *reference >= range_end_index {
-                    *reference += increase_in_size;
-                }
-            }
+        unwrap_or_panic(self.try_drain(range))
+    }
+    /// like `drain`, but returns an error instead of panicking.
+    pub fn try_drain<R>(&mut self, range: R) -> Result<Vec<T>, Error>
+    where
+        R: RangeBounds<usize>,
+    {
+        let (range_start_index, range_end_index) = self.try_range_indices(&range)?;
+        let removed = self.buf.drain(range_start_index..range_end_index).collect();
+        self.update_references(range_start_index, range_end_index, 0);
+        Ok(removed)
+    }
+    /// shortens the buffer to the given length. has no effect if the buffer is already shorter than that.
+    /// index refs that are attached to elements of the removed tail are handled according to the removal policy, and index
+    /// refs that point to the end of the buffer are moved to its new end.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len() {
+            return;
         }
-        result
+        let old_len = self.len();
+        self.buf.truncate(len);
+        self.update_references(len, old_len, 0);
+    }
+    /// removes all elements from the buffer.
+    /// index refs that point into the buffer are handled according to the removal policy.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+    /// replaces the given range with the given content, and returns the removed elements.
+    /// index refs that point to the start of the range stay there, index refs that point inside of the range are handled
+    /// according to the removal policy, and index refs that point to or after the end of the range are moved by the change
+    /// in size, so that they stay after the replacement.
+    /// if the replacement content is empty, this behaves like `drain`, and if the range is empty, this behaves like
+    /// `insert_slice`.
+    ///
+    /// the replacement content is collected and the range is validated before the buffer is modified, and the splice is
+    /// performed eagerly, so the index refs always match the elements that were actually replaced.
+    pub fn splice<R, I>(&mut self, range: R, replace_with: I) -> Vec<T>
+    where
+        R: RangeBounds<usize>,
+        I: IntoIterator<Item = T>,
+    {
+        unwrap_or_panic(self.try_splice(range, replace_with))
+    }
+    /// like `splice`, but returns an error instead of panicking.
+    pub fn try_splice<R, I>(&mut self, range: R, replace_with: I) -> Result<Vec<T>, Error>
+    where
+        R: RangeBounds<usize>,
+        I: IntoIterator<Item = T>,
+    {
+        let (range_start_index, range_end_index) = self.try_range_indices(&range)?;
+        let replacement: Vec<T> = replace_with.into_iter().collect();
+        let replacement_len = replacement.len();
+        let removed = self
+            .buf
+            .splice(range_start_index..range_end_index, replacement)
+            .collect();
+        self.update_references(range_start_index, range_end_index, replacement_len);
+        Ok(removed)
     }
     /// the length of the buffer.
     pub fn len(&self) -> usize {
@@ -126,19 +485,381 @@ impl IndexRefBuf {
     pub fn is_empty(&self) -> bool {
         self.buf.is_empty()
     }
+    /// checks if the given index ref was created by this buffer.
+    pub fn owns_index_ref(&self, index_ref: IndexRef) -> bool {
+        index_ref.buf_id == self.id
+    }
+    /// checks that the given index ref belongs to this buffer and was not released.
+    fn check_index_ref(&self, index_ref: IndexRef) -> Result<(), Error> {
+        if !self.owns_index_ref(index_ref) {
+            return Err(Error::ForeignRef);
+        }
+        let entry = &self.references[index_ref.ref_index];
+        if entry.released || entry.generation != index_ref.generation {
+            return Err(Error::ReleasedRef);
+        }
+        Ok(())
+    }
+    /// checks that content can be inserted at the given index.
+    fn check_insertion_index(&self, index: usize) -> Result<(), Error> {
+        if index > self.len() {
+            return Err(Error::OutOfBounds {
+                index,
+                len: self.len(),
+            });
+        }
+        Ok(())
+    }
+    /// unlinks the given index ref, so that edits don't move it until it is moved back using `move_index_ref`.
+    pub(crate) fn detach_index_ref(&mut self, index_ref: IndexRef) -> Result<(), Error> {
+        self.check_index_ref(index_ref)?;
+        self.ref_tree.remove(index_ref.ref_index);
+        Ok(())
+    }
+    /// moves the given index ref to the given index, keeping its gravity.
+    pub(crate) fn move_index_ref(
+        &mut self,
+        index_ref: IndexRef,
+        index: usize,
+    ) -> Result<(), Error> {
+        self.check_index_ref(index_ref)?;
+        self.check_insertion_index(index)?;
+        let gravity = self.ref_tree.gravity(index_ref.ref_index);
+        self.ref_tree.remove(index_ref.ref_index);
+        self.ref_tree.insert(index_ref.ref_index, index, gravity);
+        Ok(())
+    }
+    /// runs the given operation as a single composite operation, so that `invalidated_refs` lists the index refs
+    /// invalidated by all of its edits, and not only by its last one.
+    pub(crate) fn composite_edit<R>(&mut self, operation: impl FnOnce(&mut Self) -> R) -> R {
+        if self.composite_depth == 0 {
+            self.invalidated_refs.clear();
+        }
+        self.composite_depth += 1;
+        let result = operation(self);
+        self.composite_depth -= 1;
+        result
+    }
+    /// the state of every index ref slot, used for comparing and hashing buffers.
+    fn ref_states(&self) -> impl Iterator<Item = (&RefEntry, Option<usize>, Gravity)> + '_ {
+        self.references
+            .iter()
+            .enumerate()
+            .map(|(ref_index, entry)| {
+                (
+                    entry,
+                    self.ref_tree.index(ref_index),
+                    self.ref_tree.gravity(ref_index),
+                )
+            })
+    }
+    /// the fields and directives registered in the buffer, with their index refs moved to `UNBOUND_BUF_ID`, so they can
+    /// be compared with the ones of buffers with other ids.
+    fn registrations(&self) -> Registrations<'_, T> {
+        let id = UNBOUND_BUF_ID;
+        Registrations {
+            fixups: self
+                .fixups
+                .iter()
+                .map(|fixup| fixup.rebind(self.id, id))
+                .collect(),
+            relaxables: self
+                .relaxables
+                .iter()
+                .map(|relaxable| relaxable.rebind(self.id, id))
+                .collect(),
+            symbols: self
+                .symbols
+                .iter()
+                .map(|symbol| symbol.as_ref().map(|expr| expr.rebind(self.id, id)))
+                .collect(),
+            length_fields: self
+                .length_fields
+                .iter()
+                .map(|entry| entry.rebind(self.id, id))
+                .collect(),
+            checksums: self
+                .checksums
+                .iter()
+                .map(|checksum| checksum.rebind(self.id, id))
+                .collect(),
+            alignments: self
+                .alignments
+                .iter()
+                .map(|alignment| alignment.identity(self.id, id))
+                .collect(),
+            load_base: self.load_base,
+            segments: self
+                .segments
+                .iter()
+                .map(|segment| segment.rebind(self.id, id))
+                .collect(),
+            journal: self.journal.as_ref(),
+        }
+    }
+    /// converts the given range bounds to a start and end index, checking that the range is valid for the buffer.
+    fn try_range_indices<R: RangeBounds<usize>>(&self, range: &R) -> Result<(usize, usize), Error> {
+        let range_start_index = match range.start_bound() {
+            std::ops::Bound::Included(x) => Some(*x),
+            std::ops::Bound::Excluded(x) => x.checked_add(1),
+            std::ops::Bound::Unbounded => Some(0),
+        };
+        let range_end_index = match range.end_bound() {
+            std::ops::Bound::Included(x) => x.checked_add(1),
+            std::ops::Bound::Excluded(x) => Some(*x),
+            std::ops::Bound::Unbounded => Some(self.buf.len()),
+        };
+        match (range_start_index, range_end_index) {
+            (Some(start), Some(end)) if start <= end && end <= self.len() => Ok((start, end)),
+            _ => Err(Error::InvalidRange {
+                start: range_start_index.unwrap_or(usize::MAX),
+                end: range_end_index.unwrap_or(usize::MAX),
+                len: self.len(),
+            }),
+        }
+    }
+    /// updates the index refs after the range `start..end` was replaced with `replacement_len` elements.
+    fn update_references(&mut self, start: usize, end: usize, replacement_len: usize) {
+        if self.composite_depth == 0 {
+            self.invalidated_refs.clear();
+        }
+        self.record_edit(JournalEdit::Splice {
+            start,
+            end,
+            replacement_len,
+            policy: self.removal_policy,
+        });
+        let removed_len = end - start;
+        let offset = replacement_len as isize - removed_len as isize;
+        if removed_len == 0 {
+            // a plain insertion, where only refs with left gravity stay in place.
+            self.ref_tree
+                .shift_from(RefKey::new(start, Gravity::Right), offset);
+            self.realign_after_edit(start, offset);
+            return;
+        }
+        let (affected_start, affected_end) = if replacement_len == 0 {
+            // a plain removal, where refs with left gravity are attached to the element before their index.
+            (
+                RefKey::new(start, Gravity::Right),
+                RefKey::new(end, Gravity::Right),
+            )
+        } else {
+            // a replacement, where refs to the start of the range stay at the start of the replacement.
+            (
+                RefKey::new(start + 1, Gravity::Left),
+                RefKey::new(end, Gravity::Left),
+            )
+        };
+        let mut affected = self.ref_tree.take_range(affected_start, affected_end);
+        self.ref_tree.shift_from(affected_end, offset);
+
+        affected.sort_unstable();
+        let is_removed = |index: usize| start < index && index < end;
+        for &(ref_index, index) in &affected {
+            let gravity = self.ref_tree.gravity(ref_index);
+            // the boundaries of a range ref are exclusive, so they have no target of their own. they are only handled
+            // according to the removal policy if the removal strictly contains the whole range.
+            let is_kept_boundary =
+                self.references[ref_index]
+                    .range_partner
+                    .is_some_and(|partner| {
+                        let partner_index = affected
+                            .binary_search_by_key(&partner, |&(ref_index, _)| ref_index)
+                            .ok()
+                            .map(|position| affected[position].1);
+                        !(is_removed(index) && partner_index.is_some_and(is_removed))
+                    });
+            if is_kept_boundary {
+                self.ref_tree.insert(ref_index, start, gravity);
+                continue;
+            }
+            match self.removal_policy {
+                RemovalPolicy::ClampToStart => self.ref_tree.insert(ref_index, start, gravity),
+                RemovalPolicy::ClampToEnd => {
+                    self.ref_tree
+                        .insert(ref_index, start + replacement_len, gravity)
+                }
+                RemovalPolicy::Invalidate => self.invalidated_refs.push(IndexRef {
+                    buf_id: self.id,
+                    ref_index,
+                    generation: self.references[ref_index].generation,
+                }),
+            }
+        }
+        self.realign_after_edit(start, offset);
+    }
+    /// moves the index refs with `Gravity::End` that pointed to the old end of the buffer to its new end.
+    fn update_end_references(&mut self, old_len: usize) {
+        if self.composite_depth == 0 {
+            self.invalidated_refs.clear();
+        }
+        self.record_edit(JournalEdit::Append {
+            old_len,
+            len: self.len() - old_len,
+        });
+        let offset = (self.len() - old_len) as isize;
+        self.ref_tree
+            .shift_from(RefKey::new(old_len, Gravity::End), offset);
+    }
+}
+impl<T: Clone> Clone for IndexRefBuf<T> {
+    fn clone(&self) -> Self {
+        // the clone gets a new id, since it may diverge from this buffer.
+        let id = alloc_buf_id();
+        Self {
+            id,
+            buf: self.buf.clone(),
+            references: self.references.clone(),
+            ref_tree: self.ref_tree.clone(),
+            free_ref_slots: self.free_ref_slots.clone(),
+            removal_policy: self.removal_policy,
+            invalidated_refs: self
+                .invalidated_refs
+                .iter()
+                .map(|index_ref| index_ref.rebind(self.id, id))
+                .collect(),
+            composite_depth: 0,
+            fixups: self
+                .fixups
+                .iter()
+                .map(|fixup| fixup.rebind(self.id, id))
+                .collect(),
+            relaxables: self
+                .relaxables
+                .iter()
+                .map(|relaxable| relaxable.rebind(self.id, id))
+                .collect(),
+            symbols: self
+                .symbols
+                .iter()
+                .map(|symbol| symbol.as_ref().map(|value| value.rebind(self.id, id)))
+                .collect(),
+            length_fields: self
+                .length_fields
+                .iter()
+                .map(|entry| entry.rebind(self.id, id))
+                .collect(),
+            checksums: self
+                .checksums
+                .iter()
+                .map(|checksum| checksum.rebind(self.id, id))
+                .collect(),
+            alignments: self
+                .alignments
+                .iter()
+                .map(|alignment| alignment.rebind(self.id, id))
+                .collect(),
+            realigning: false,
+            load_base: self.load_base,
+            segments: self
+                .segments
+                .iter()
+                .map(|segment| segment.rebind(self.id, id))
+                .collect(),
+            journal: self.journal.clone(),
+        }
+    }
+}
+impl<T: PartialEq> PartialEq for IndexRefBuf<T> {
+    /// buffers are equal if they have the same content, the same index ref states, and the same registered fields and
+    /// directives, regardless of their ids.
+    fn eq(&self, other: &Self) -> bool {
+        self.buf == other.buf
+            && self.ref_states().eq(other.ref_states())
+            && self.removal_policy == other.removal_policy
+            && self.registrations() == other.registrations()
+    }
 }
-impl Deref for IndexRefBuf {
-    type Target = [u8];
+impl<T: Eq> Eq for IndexRefBuf<T> {}
+impl<T: Hash> Hash for IndexRefBuf<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.buf.hash(state);
+        for ref_state in self.ref_states() {
+            ref_state.hash(state);
+        }
+        self.removal_policy.hash(state);
+        self.registrations().hash(state);
+    }
+}
+impl<T> Default for IndexRefBuf<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<T> Deref for IndexRefBuf<T> {
+    type Target = [T];
 
     fn deref(&self) -> &Self::Target {
         &self.buf
     }
 }
 
+/// the fields and directives registered in a buffer, detached from its id.
+#[derive(PartialEq, Eq, Hash)]
+struct Registrations<'a, T> {
+    fixups: Vec<Fixup>,
+    relaxables: Vec<Relaxable>,
+    symbols: Vec<Option<Expr>>,
+    length_fields: Vec<LengthFieldEntry>,
+    checksums: Vec<Checksum>,
+    alignments: Vec<(IndexRangeRef, usize, &'a [T])>,
+    load_base: u64,
+    segments: Vec<Segment>,
+    journal: Option<&'a Journal>,
+}
+
+/// a reference to an auto updating range in a buffer, made of an index ref to each of its boundaries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IndexRangeRef {
+    start: IndexRef,
+    end: IndexRef,
+}
+impl IndexRangeRef {
+    /// the same range ref, but moved from the buffer with id `old_buf_id` to the one with id `buf_id`. used when cloning
+    /// buffers.
+    pub(crate) fn rebind(self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            start: self.start.rebind(old_buf_id, buf_id),
+            end: self.end.rebind(old_buf_id, buf_id),
+        }
+    }
+    /// the index ref to the start of the range.
+    pub fn start(&self) -> IndexRef {
+        self.start
+    }
+    /// the index ref to the end of the range.
+    pub fn end(&self) -> IndexRef {
+        self.end
+    }
+}
+
+/// the state of a single index ref slot. the index and gravity of the slot are stored in the ref tree.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RefEntry {
+    /// incremented every time the slot of the entry is released, to detect stale index refs.
+    generation: u32,
+    released: bool,
+    /// the slot of the other boundary, if this slot is a boundary of a range ref.
+    range_partner: Option<usize>,
+}
+
 /// a reference to an auto updating index in a buffer.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct IndexRef {
+    buf_id: u64,
     ref_index: usize,
+    generation: u32,
+}
+impl IndexRef {
+    /// the same index ref, but moved from the buffer with id `old_buf_id` to the one with id `buf_id`. used when cloning
+    /// buffers. index refs of other buffers are left unchanged, so that they are still detected as foreign refs.
+    pub(crate) fn rebind(self, old_buf_id: u64, buf_id: u64) -> Self {
+        if self.buf_id != old_buf_id {
+            return self;
+        }
+        Self { buf_id, ..self }
+    }
 }
 
 #[test]
@@ -195,3 +916,346 @@ pub fn make_sure_reference_points_to_same_element_after_modifications() {
         assert_eq!(buf[final_index], 1);
     }
 }
+
+#[test]
+pub fn make_sure_generic_buffer_updates_references() {
+    let mut buf: IndexRefBuf<u32> = IndexRefBuf::from_vec(vec![10, 20, 30]);
+    let index_ref = buf.create_index_ref(2);
+    buf.insert(0, 5);
+    buf.insert_slice(1, &[6, 7]);
+    buf.splice(0..1, [1, 2, 3]);
+    assert_eq!(buf[buf.read_index_ref(index_ref)], 30);
+}
+
+#[test]
+pub fn make_sure_removals_follow_the_removal_policy() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2, 3, 4, 5, 6, 7]);
+    let before = buf.create_index_ref(1);
+    let inside = buf.create_index_ref(3);
+    let after = buf.create_index_ref(6);
+
+    buf.remove(0);
+    assert_eq!(buf[buf.read_index_ref(before)], 1);
+
+    // replace [2, 3, 4] with [9], clamping the ref inside to the end of the replacement.
+    buf.set_removal_policy(RemovalPolicy::ClampToEnd);
+    buf.splice(1..4, [9]);
+    assert_eq!(buf.read_index_ref(inside), 2);
+    assert_eq!(buf[buf.read_index_ref(after)], 6);
+
+    buf.drain(..2);
+    assert_eq!(buf.read_index_ref(before), 0);
+    assert_eq!(buf[buf.read_index_ref(after)], 6);
+
+    buf.set_removal_policy(RemovalPolicy::Invalidate);
+    buf.truncate(1);
+    assert_eq!(buf.get_index_ref(after), None);
+    assert_eq!(buf.invalidated_refs(), &[after]);
+    buf.clear();
+    assert!(buf.is_empty());
+    assert!(!buf.is_index_ref_alive(inside));
+    assert_eq!(buf.invalidated_refs(), &[before, inside]);
+}
+
+#[test]
+pub fn make_sure_gravity_breaks_ties_at_the_insertion_index() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2]);
+    let left = buf.create_index_ref_with_gravity(1, Gravity::Left);
+    let right = buf.create_index_ref_with_gravity(1, Gravity::Right);
+    let end = buf.create_index_ref_with_gravity(3, Gravity::End);
+    let not_end = buf.create_index_ref(3);
+
+    buf.insert_slice(1, &[7, 7]);
+    assert_eq!(buf.read_index_ref(left), 1);
+    assert_eq!(buf.read_index_ref(right), 3);
+
+    buf.push(3);
+    buf.extend_from_slice(&[4, 5]);
+    assert_eq!(buf.read_index_ref(end), buf.len());
+    assert_eq!(buf.read_index_ref(not_end), 5);
+
+    // removing the element before a left ref kills it, while the right ref survives.
+    buf.set_removal_policy(RemovalPolicy::Invalidate);
+    buf.drain(0..1);
+    assert!(!buf.is_index_ref_alive(left));
+    assert_eq!(buf[buf.read_index_ref(right)], 1);
+}
+
+#[test]
+pub fn make_sure_released_ref_slots_are_reused_and_stale_refs_are_detected() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2]);
+    let released = buf.create_index_ref(1);
+    buf.release_index_ref(released);
+    let reused = buf.create_index_ref(2);
+    assert_eq!(buf.references.len(), 1);
+    assert_eq!(buf.get_index_ref(released), None);
+    assert_eq!(buf.read_index_ref(reused), 2);
+}
+
+#[test]
+pub fn make_sure_refs_of_other_buffers_are_detected() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2]);
+    let index_ref = buf.create_index_ref(1);
+    let mut clone = buf.clone();
+    let clone_ref = clone.create_index_ref(2);
+    assert!(!clone.owns_index_ref(index_ref));
+    assert_eq!(clone.get_index_ref(index_ref), None);
+    assert_eq!(buf.get_index_ref(clone_ref), None);
+    assert_eq!(buf.read_index_ref(index_ref), 1);
+}
+
+#[test]
+pub fn make_sure_cloning_keeps_foreign_refs_foreign() {
+    let mut other = IndexRefBuf::from_vec(vec![0u8; 8]);
+    let foreign_site = other.create_index_ref(0);
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 8]);
+    let target = buf.create_index_ref(5);
+    buf.add_fixup(Fixup::absolute(foreign_site, target, 1, Endianness::Little));
+
+    let mut clone = buf.clone();
+    assert_eq!(buf.finalize(), Err(vec![Error::ForeignRef]));
+    assert_eq!(clone.finalize(), Err(vec![Error::ForeignRef]));
+    assert_eq!(&clone[..], &[0; 8]);
+}
+
+#[test]
+#[should_panic(expected = "different buffer")]
+pub fn make_sure_reading_a_foreign_ref_panics() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2]);
+    let index_ref = buf.create_index_ref(1);
+    IndexRefBuf::from_vec(vec![0u8, 1, 2]).read_index_ref(index_ref);
+}
+
+#[test]
+pub fn make_sure_range_refs_track_their_span() {
+    let mut buf = IndexRefBuf::from_vec(b"ab(cd)ef".to_vec());
+    let inclusive = buf.create_range_ref(3..5);
+    let exclusive = buf.create_range_ref_with_gravity(3..5, Gravity::Right, Gravity::Left);
+
+    buf.insert(3, b'x');
+    buf.insert(6, b'y');
+    buf.insert_slice(0, b"__");
+    assert_eq!(buf.range_ref_slice(inclusive), b"xcdy");
+    assert_eq!(buf.range_ref_slice(exclusive), b"cd");
+
+    buf.splice(6..7, *b"DDD");
+    assert_eq!(buf.read_range_ref(inclusive), 5..11);
+    assert_eq!(buf.range_ref_slice(exclusive), b"DDDd");
+
+    // removing the whole content of the inclusive range keeps it alive, as an empty range.
+    buf.drain(5..11);
+    assert_eq!(buf.get_range_ref(inclusive), Some(5..5));
+    assert_eq!(buf.get_range_ref(exclusive), None);
+}
+
+#[test]
+pub fn make_sure_removing_neighbours_of_a_range_keeps_it_alive() {
+    for (removed, expected) in [(1..2, 1..3), (4..5, 2..4), (1..3, 1..2), (3..5, 2..3)] {
+        let mut buf = IndexRefBuf::from_vec(b"abcdef".to_vec());
+        let range_ref = buf.create_range_ref(2..4);
+        let exclusive = buf.create_range_ref_with_gravity(2..4, Gravity::Right, Gravity::Left);
+        let empty = buf.create_range_ref_with_gravity(2..2, Gravity::Left, Gravity::Left);
+        buf.drain(removed.clone());
+        assert_eq!(buf.get_range_ref(range_ref), Some(expected.clone()));
+        assert_eq!(buf.get_range_ref(exclusive), Some(expected.clone()));
+        let empty_is_contained = removed.start < 2 && 2 < removed.end;
+        assert_eq!(buf.get_range_ref(empty).is_none(), empty_is_contained);
+    }
+
+    // only a removal which strictly contains the range invalidates it.
+    let mut buf = IndexRefBuf::from_vec(b"abcdef".to_vec());
+    let range_ref = buf.create_range_ref(2..4);
+    buf.drain(1..5);
+    assert_eq!(buf.get_range_ref(range_ref), None);
+}
+
+#[test]
+pub fn make_sure_failed_operations_leave_the_buffer_unchanged() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2]);
+    let index_ref = buf.create_index_ref(1);
+    buf.checkpoint();
+    let before = buf.clone();
+
+    assert_eq!(
+        buf.try_insert(4, 0),
+        Err(Error::OutOfBounds { index: 4, len: 3 })
+    );
+    assert_eq!(
+        buf.try_remove(3),
+        Err(Error::OutOfBounds { index: 3, len: 3 })
+    );
+    let (start, end) = (2, 1);
+    assert!(matches!(
+        buf.try_splice(start..end, [0]),
+        Err(Error::InvalidRange { .. })
+    ));
+    assert!(matches!(
+        buf.try_drain(..=3),
+        Err(Error::InvalidRange { .. })
+    ));
+    assert_eq!(
+        buf.try_read_index_ref(IndexRefBuf::from_vec(vec![0u8]).create_index_ref(0)),
+        Err(Error::ForeignRef)
+    );
+    assert_eq!(
+        buf.try_create_alignment(1, 0, &[0x90]),
+        Err(Error::InvalidAlignment)
+    );
+    let encoding = LengthEncoding::Fixed {
+        width: 0,
+        endianness: Endianness::Little,
+    };
+    assert_eq!(
+        buf.try_create_tlv(1, &[0x30], encoding),
+        Err(Error::InvalidWidth { width: 0 })
+    );
+    assert_eq!(buf, before);
+
+    // registrations are compared too, so leaking any of them is noticed.
+    let mut registered = before.clone();
+    let site = registered.create_index_ref(0);
+    let target = registered.create_index_ref(2);
+    let unregistered = registered.clone();
+    assert_eq!(registered, unregistered);
+    registered.add_fixup(Fixup::absolute(site, target, 1, Endianness::Little));
+    assert_ne!(registered, unregistered);
+
+    buf.release_index_ref(index_ref);
+    assert_eq!(
+        buf.try_release_index_ref(index_ref),
+        Err(Error::ReleasedRef)
+    );
+    let deleted = buf.create_index_ref(1);
+    buf.remove(1);
+    assert_eq!(buf.try_read_index_ref(deleted), Err(Error::DeletedTarget));
+}
+
+#[test]
+pub fn make_sure_splice_handles_every_kind_of_bound() {
+    use std::ops::Bound;
+
+    let mut buf = IndexRefBuf::from_vec(vec![0u8, 1, 2, 3, 4]);
+    let after = buf.create_index_ref(3);
+    let removed = buf.splice((Bound::Excluded(0), Bound::Included(2)), [9]);
+    assert_eq!(removed, vec![1, 2]);
+    assert_eq!(&buf[..], &[0, 9, 3, 4]);
+    assert_eq!(buf[buf.read_index_ref(after)], 3);
+
+    // the replacement doesn't have to know its length in advance.
+    buf.splice(..1, (5..8).filter(|x| x % 2 == 1));
+    assert_eq!(&buf[..], &[5, 7, 9, 3, 4]);
+    assert_eq!(buf[buf.read_index_ref(after)], 3);
+
+    assert!(buf
+        .try_splice((Bound::Excluded(usize::MAX), Bound::Unbounded), [0])
+        .is_err());
+    assert_eq!(&buf[..], &[5, 7, 9, 3, 4]);
+}
+
+#[test]
+pub fn make_sure_ref_tree_matches_a_linear_scan() {
+    // a simple linear model of the index ref update rules, which the ref tree must agree with.
+    fn model_update(
+        refs: &mut [(Option<usize>, Gravity)],
+        policy: RemovalPolicy,
+        start: usize,
+        end: usize,
+        replacement_len: usize,
+    ) {
+        for (index, gravity) in refs.iter_mut() {
+            let Some(cur) = *index else {
+                continue;
+            };
+            let is_left = *gravity == Gravity::Left;
+            let stays = if start == end || replacement_len == 0 {
+                cur < start || (cur == start && is_left)
+            } else {
+                cur <= start
+            };
+            if stays {
+                continue;
+            }
+            let removed = if replacement_len == 0 {
+                cur < end || (cur == end && is_left)
+            } else {
+                cur < end
+            };
+            *index = if !removed {
+                Some(cur - (end - start) + replacement_len)
+            } else {
+                match policy {
+                    RemovalPolicy::ClampToStart => Some(start),
+                    RemovalPolicy::ClampToEnd => Some(start + replacement_len),
+                    RemovalPolicy::Invalidate => None,
+                }
+            };
+        }
+    }
+
+    let mut rng_state = 12345u64;
+    let mut rand = move |bound: usize| {
+        rng_state ^= rng_state << 13;
+        rng_state ^= rng_state >> 7;
+        rng_state ^= rng_state << 17;
+        (rng_state % bound as u64) as usize
+    };
+    let gravities = [Gravity::Left, Gravity::Right, Gravity::End];
+    let policies = [
+        RemovalPolicy::ClampToStart,
+        RemovalPolicy::ClampToEnd,
+        RemovalPolicy::Invalidate,
+    ];
+
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 16]);
+    let mut refs = Vec::new();
+    let mut model = Vec::new();
+    for _ in 0..2000 {
+        let policy = policies[rand(policies.len())];
+        buf.set_removal_policy(policy);
+        let len = buf.len();
+        match rand(6) {
+            0 if !refs.is_empty() => {
+                let i = rand(refs.len());
+                buf.release_index_ref(refs.swap_remove(i));
+                model.swap_remove(i);
+            }
+            0 | 5 => {
+                let index = rand(len + 1);
+                let gravity = gravities[rand(gravities.len())];
+                refs.push(buf.create_index_ref_with_gravity(index, gravity));
+                model.push((Some(index), gravity));
+            }
+            1 => {
+                let index = rand(len + 1);
+                let count = rand(4);
+                buf.insert_slice(index, &vec![0; count]);
+                model_update(&mut model, policy, index, index, count);
+            }
+            2 => {
+                let start = rand(len + 1);
+                let end = start + rand(len - start + 1).min(4);
+                let count = rand(4);
+                buf.splice(start..end, vec![0; count]);
+                model_update(&mut model, policy, start, end, count);
+            }
+            3 => {
+                let start = rand(len + 1);
+                let end = start + rand(len - start + 1).min(4);
+                buf.drain(start..end);
+                model_update(&mut model, policy, start, end, 0);
+            }
+            _ => {
+                let count = rand(3);
+                buf.extend_from_slice(&vec![0; count]);
+                for (index, gravity) in &mut model {
+                    if *gravity == Gravity::End && *index == Some(len) {
+                        *index = Some(len + count);
+                    }
+                }
+            }
+        }
+        for (index_ref, (index, _)) in refs.iter().zip(&model) {
+            assert_eq!(buf.get_index_ref(*index_ref), *index);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! placeholders, which hold a position in the buffer for content whose size is not known yet.
+
+use crate::{unwrap_or_panic, Error, Gravity, IndexRangeRef, IndexRef, IndexRefBuf};
+
+/// a reference to a placeholder in a buffer, which is a range holding a position for content that is filled later.
+///
+/// both boundaries of a placeholder stick to the element before them, so content inserted at the position of the
+/// placeholder by other edits goes after it. removing the elements on either side of the placeholder keeps it alive, so
+/// only removing a range which strictly contains it invalidates it. filling the placeholder replaces its content, and moves its end and every
+/// index ref after it according to the size of the new content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PlaceholderRef {
+    region: IndexRangeRef,
+}
+impl PlaceholderRef {
+    /// the range ref of the current content of the placeholder.
+    pub fn range(&self) -> IndexRangeRef {
+        self.region
+    }
+    /// the index ref to the start of the placeholder.
+    pub fn start(&self) -> IndexRef {
+        self.region.start()
+    }
+    /// the index ref to the end of the placeholder.
+    pub fn end(&self) -> IndexRef {
+        self.region.end()
+    }
+}
+
+impl<T> IndexRefBuf<T> {
+    /// reserves an empty placeholder at the given index, to be filled later using `fill_placeholder`.
+    pub fn reserve_placeholder(&mut self, at: usize) -> PlaceholderRef {
+        unwrap_or_panic(self.try_reserve_placeholder(at))
+    }
+    /// like `reserve_placeholder`, but returns an error instead of panicking.
+    pub fn try_reserve_placeholder(&mut self, at: usize) -> Result<PlaceholderRef, Error> {
+        let region =
+            self.try_create_range_ref_with_gravity(at..at, Gravity::Left, Gravity::Left)?;
+        Ok(PlaceholderRef { region })
+    }
+    /// replaces the content of the given placeholder with the given content, which may have any length, and returns the
+    /// previous content. index refs after the placeholder are moved by the change in size, while index refs inside of the
+    /// previous content are handled according to the removal policy.
+    pub fn fill_placeholder<I>(&mut self, placeholder: PlaceholderRef, content: I) -> Vec<T>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        unwrap_or_panic(self.try_fill_placeholder(placeholder, content))
+    }
+    /// like `fill_placeholder`, but returns an error instead of panicking.
+    pub fn try_fill_placeholder<I>(
+        &mut self,
+        placeholder: PlaceholderRef,
+        content: I,
+    ) -> Result<Vec<T>, Error>
+    where
+        I: IntoIterator<Item = T>,
+    {
+        let range = self.try_read_range_ref(placeholder.region)?;
+        let content: Vec<T> = content.into_iter().collect();
+        let new_end = range.start + content.len();
+        // the end sticks to the element before it, so it is detached while splicing, to stay after the new content.
+        self.detach_index_ref(placeholder.end())?;
+        let previous = self.try_splice(range, content)?;
+        self.move_index_ref(placeholder.end(), new_end)?;
+        Ok(previous)
+    }
+}
+
+#[test]
+pub fn make_sure_placeholders_can_be_filled_later() {
+    // a header whose size is only known after the body was emitted.
+    let mut buf = IndexRefBuf::new();
+    let header = buf.reserve_placeholder(0);
+    buf.insert_slice(0, b"body");
+    let body = buf.create_index_ref(0);
+    let before_body = buf.create_index_ref_with_gravity(0, Gravity::Left);
+    assert_eq!(buf.read_range_ref(header.range()), 0..0);
+
+    buf.fill_placeholder(header, *b"HDR:");
+    assert_eq!(&buf[..], b"HDR:body");
+    assert_eq!(buf.read_range_ref(header.range()), 0..4);
+    assert_eq!(buf.read_index_ref(body), 4);
+    assert_eq!(buf.read_index_ref(before_body), 0);
+
+    assert_eq!(buf.fill_placeholder(header, *b"H:"), b"HDR:");
+    assert_eq!(&buf[..], b"H:body");
+    assert_eq!(buf.read_index_ref(body), 2);
+    buf.fill_placeholder(header, []);
+    assert_eq!(buf.read_range_ref(header.range()), 0..0);
+    assert_eq!(buf.read_index_ref(body), 0);
+}
+
+#[test]
+pub fn make_sure_placeholders_survive_removing_their_neighbours() {
+    let mut buf = IndexRefBuf::from_vec(b"abcdef".to_vec());
+    let placeholder = buf.reserve_placeholder(2);
+    let after = buf.create_index_ref(3);
+    buf.remove(1);
+    buf.remove(1);
+    assert_eq!(&buf[..], b"adef");
+    assert_eq!(buf.get_range_ref(placeholder.range()), Some(1..1));
+
+    buf.fill_placeholder(placeholder, *b"XY");
+    assert_eq!(&buf[..], b"aXYdef");
+    assert_eq!(buf.read_range_ref(placeholder.range()), 1..3);
+    assert_eq!(buf.read_index_ref(after), 3);
+
+    buf.drain(..);
+    assert_eq!(buf.get_range_ref(placeholder.range()), None);
+}
@@ -0,0 +1,264 @@
+//! a balanced tree of index refs, which allows shifting all index refs after some point in logarithmic time.
+//!
+//! the tree is a treap ordered by the key of each index ref, which is its index and its gravity. edits of the buffer
+//! never change the relative order of index refs, except for ones that point into a removed range, which are taken
+//! out of the tree and re-inserted. this allows shifting all index refs after some key by lazily adding an offset to
+//! the root of a subtree.
+
+use crate::Gravity;
+
+/// a null node index.
+const NIL: usize = usize::MAX;
+
+/// the key by which index refs are ordered in the tree.
+/// at the same index, refs with left gravity come first, since they stay in place when inserting at that index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct RefKey {
+    index: usize,
+    gravity_rank: u8,
+}
+impl RefKey {
+    /// creates the key of an index ref with the given index and gravity.
+    pub(crate) fn new(index: usize, gravity: Gravity) -> Self {
+        let gravity_rank = match gravity {
+            Gravity::Left => 0,
+            Gravity::Right => 1,
+            Gravity::End => 2,
+        };
+        Self {
+            index,
+            gravity_rank,
+        }
+    }
+}
+
+/// a node in the tree. every index ref slot has a node, which may or may not currently be linked into the tree.
+#[derive(Debug, Clone)]
+struct Node {
+    left: usize,
+    right: usize,
+    parent: usize,
+    priority: u64,
+    /// the index of this node, not including the pending offsets of its ancestors.
+    index: usize,
+    /// a pending offset which should be added to all descendants of this node. stored as a wrapping offset.
+    pending_offset: usize,
+    gravity: Gravity,
+    linked: bool,
+}
+
+/// a balanced tree of index refs.
+#[derive(Debug, Clone)]
+pub(crate) struct RefTree {
+    nodes: Vec<Node>,
+    root: usize,
+    rng_state: u64,
+}
+impl RefTree {
+    /// creates a new empty tree.
+    pub(crate) fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            root: NIL,
+            rng_state: 0,
+        }
+    }
+    /// links the given slot into the tree with the given index and gravity.
+    /// the slot must either be unlinked, or be the next unused slot.
+    pub(crate) fn insert(&mut self, slot: usize, index: usize, gravity: Gravity) {
+        let node = Node {
+            left: NIL,
+            right: NIL,
+            parent: NIL,
+            priority: self.next_priority(),
+            index,
+            pending_offset: 0,
+            gravity,
+            linked: true,
+        };
+        if slot == self.nodes.len() {
+            self.nodes.push(node);
+        } else {
+            debug_assert!(!self.nodes[slot].linked);
+            self.nodes[slot] = node;
+        }
+        let (before, after) = self.split(self.root, RefKey::new(index, gravity));
+        let before = self.merge(before, slot);
+        self.root = self.merge(before, after);
+        self.detach_root();
+    }
+    /// unlinks the given slot from the tree. does nothing if the slot is not linked.
+    pub(crate) fn remove(&mut self, slot: usize) {
+        if !self.is_linked(slot) {
+            return;
+        }
+        self.push_path(slot);
+        let node = &self.nodes[slot];
+        let (parent, left, right) = (node.parent, node.left, node.right);
+        let replacement = self.merge(left, right);
+        if parent == NIL {
+            self.root = replacement;
+            self.detach_root();
+        } else if self.nodes[parent].left == slot {
+            self.set_left(parent, replacement);
+        } else {
+            self.set_right(parent, replacement);
+        }
+        self.nodes[slot].linked = false;
+    }
+    /// the index of the given slot, or `None` if it is not linked.
+    pub(crate) fn index(&self, slot: usize) -> Option<usize> {
+        if !self.is_linked(slot) {
+            return None;
+        }
+        let mut index = self.nodes[slot].index;
+        let mut cur = self.nodes[slot].parent;
+        while cur != NIL {
+            index = index.wrapping_add(self.nodes[cur].pending_offset);
+            cur = self.nodes[cur].parent;
+        }
+        Some(index)
+    }
+    /// the gravity of the given slot.
+    pub(crate) fn gravity(&self, slot: usize) -> Gravity {
+        self.nodes[slot].gravity
+    }
+    /// adds the given offset to the index of every linked slot with a key greater than or equal to the given key.
+    pub(crate) fn shift_from(&mut self, key: RefKey, offset: isize) {
+        if offset == 0 {
+            return;
+        }
+        let (before, after) = self.split(self.root, key);
+        if after != NIL {
+            let node = &mut self.nodes[after];
+            node.index = node.index.wrapping_add(offset as usize);
+            node.pending_offset = node.pending_offset.wrapping_add(offset as usize);
+        }
+        self.root = self.merge(before, after);
+        self.detach_root();
+    }
+    /// unlinks every slot with a key in the range `start..end`, and returns the unlinked slots along with the index each
+    /// of them had.
+    pub(crate) fn take_range(&mut self, start: RefKey, end: RefKey) -> Vec<(usize, usize)> {
+        let (before, rest) = self.split(self.root, start);
+        let (taken, after) = self.split(rest, end);
+        self.root = self.merge(before, after);
+        self.detach_root();
+
+        // the pending offsets inside of the taken subtree were not pushed, so they are accumulated on the way down.
+        let mut slots = Vec::new();
+        let mut stack = vec![(taken, 0usize)];
+        while let Some((cur, pending_offset)) = stack.pop() {
+            if cur == NIL {
+                continue;
+            }
+            let node = &mut self.nodes[cur];
+            node.linked = false;
+            slots.push((cur, node.index.wrapping_add(pending_offset)));
+            let pending_offset = pending_offset.wrapping_add(node.pending_offset);
+            stack.push((node.left, pending_offset));
+            stack.push((node.right, pending_offset));
+        }
+        slots
+    }
+    /// checks if the given slot is linked into the tree.
+    fn is_linked(&self, slot: usize) -> bool {
+        self.nodes.get(slot).is_some_and(|node| node.linked)
+    }
+    /// the key of the given node. only accurate if the pending offsets of all of its ancestors were pushed.
+    fn key(&self, node: usize) -> RefKey {
+        RefKey::new(self.nodes[node].index, self.nodes[node].gravity)
+    }
+    /// generates a random priority for a new node.
+    fn next_priority(&mut self) -> u64 {
+        // splitmix64
+        self.rng_state = self.rng_state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.rng_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+    /// applies the pending offset of the given node to its children.
+    fn push(&mut self, node: usize) {
+        let offset = self.nodes[node].pending_offset;
+        if offset == 0 {
+            return;
+        }
+        for child in [self.nodes[node].left, self.nodes[node].right] {
+            if child != NIL {
+                let child = &mut self.nodes[child];
+                child.index = child.index.wrapping_add(offset);
+                child.pending_offset = child.pending_offset.wrapping_add(offset);
+            }
+        }
+        self.nodes[node].pending_offset = 0;
+    }
+    /// pushes the pending offsets of all nodes from the root down to the given node, including the node itself.
+    fn push_path(&mut self, node: usize) {
+        let mut path = Vec::new();
+        let mut cur = node;
+        while cur != NIL {
+            path.push(cur);
+            cur = self.nodes[cur].parent;
+        }
+        for cur in path.into_iter().rev() {
+            self.push(cur);
+        }
+    }
+    fn set_left(&mut self, node: usize, child: usize) {
+        self.nodes[node].left = child;
+        if child != NIL {
+            self.nodes[child].parent = node;
+        }
+    }
+    fn set_right(&mut self, node: usize, child: usize) {
+        self.nodes[node].right = child;
+        if child != NIL {
+            self.nodes[child].parent = node;
+        }
+    }
+    /// clears the parent of the root node.
+    fn detach_root(&mut self) {
+        if self.root != NIL {
+            self.nodes[self.root].parent = NIL;
+        }
+    }
+    /// splits the given subtree into a subtree of nodes with keys less than the given key, and a subtree of the rest.
+    /// the parents of the returned subtrees are left unspecified.
+    fn split(&mut self, node: usize, key: RefKey) -> (usize, usize) {
+        if node == NIL {
+            return (NIL, NIL);
+        }
+        self.push(node);
+        if self.key(node) < key {
+            let (less, rest) = self.split(self.nodes[node].right, key);
+            self.set_right(node, less);
+            (node, rest)
+        } else {
+            let (less, rest) = self.split(self.nodes[node].left, key);
+            self.set_left(node, rest);
+            (less, node)
+        }
+    }
+    /// merges the given subtrees, where all keys of the first are less than or equal to all keys of the second.
+    /// the parent of the returned subtree is left unspecified.
+    fn merge(&mut self, first: usize, second: usize) -> usize {
+        if first == NIL {
+            return second;
+        }
+        if second == NIL {
+            return first;
+        }
+        if self.nodes[first].priority > self.nodes[second].priority {
+            self.push(first);
+            let right = self.merge(self.nodes[first].right, second);
+            self.set_right(first, right);
+            first
+        } else {
+            self.push(second);
+            let left = self.merge(first, self.nodes[second].left);
+            self.set_left(second, left);
+            second
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! branch relaxation, which grows short encodings of instructions to longer ones when their displacement doesn't fit.
+
+use crate::{
+    fixup::{check_width, encode_int},
+    unwrap_or_panic, Endianness, Error, Expr, FixupKind, Gravity, IndexRangeRef, IndexRefBuf,
+};
+
+/// one possible encoding of a relaxable instruction, made of a template of the instruction bytes and the location of the
+/// field in which its displacement is written.
+///
+/// the displacement is computed like the value of a fixup whose patch site is the field. since the target can be any
+/// expression, relaxation can also be used for fields whose encoding size depends on their value. for example, the x86 `jmp rel8`
+/// encoding is `[0xeb, 0]` with a relative 1 byte field at offset 1 and an addend of -1, and its `jmp rel32` encoding is
+/// `[0xe9, 0, 0, 0, 0]` with a relative 4 byte field at offset 1 and an addend of -4.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RelaxVariant {
+    template: Vec<u8>,
+    field_offset: usize,
+    width: usize,
+    endianness: Endianness,
+    kind: FixupKind,
+    addend: i64,
+}
+impl RelaxVariant {
+    /// creates a new variant. the width of the field is in bytes, and must be between 1 and 8.
+    pub fn new(
+        template: Vec<u8>,
+        field_offset: usize,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Self {
+        unwrap_or_panic(Self::try_new(
+            template,
+            field_offset,
+            width,
+            endianness,
+            kind,
+        ))
+    }
+    /// like `new`, but returns an error instead of panicking.
+    pub fn try_new(
+        template: Vec<u8>,
+        field_offset: usize,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Result<Self, Error> {
+        check_width(width)?;
+        if field_offset.saturating_add(width) > template.len() {
+            return Err(Error::InvalidRelaxVariants);
+        }
+        Ok(Self {
+            template,
+            field_offset,
+            width,
+            endianness,
+            kind,
+            addend: 0,
+        })
+    }
+    /// adds the given addend to the displacement of the variant.
+    pub fn with_addend(self, addend: i64) -> Self {
+        Self { addend, ..self }
+    }
+    /// the length of the encoding.
+    pub fn len(&self) -> usize {
+        self.template.len()
+    }
+    /// checks if the encoding is empty. variants are never empty, since they contain their field.
+    pub fn is_empty(&self) -> bool {
+        self.template.is_empty()
+    }
+    /// encodes the variant, given the index at which it is located and the value of its target, or returns `None` if the
+    /// displacement doesn't fit in the field.
+    fn encode(&self, start: usize, target: i128) -> Option<Vec<u8>> {
+        let site = start + self.field_offset;
+        let value = self.kind.value(site, target, self.addend)?;
+        let field = encode_int(value, self.width, self.endianness, self.kind.is_signed())?;
+        let mut bytes = self.template.clone();
+        bytes[self.field_offset..self.field_offset + self.width].copy_from_slice(&field);
+        Some(bytes)
+    }
+}
+
+/// a relaxable instruction in the buffer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct Relaxable {
+    region: IndexRangeRef,
+    target: Expr,
+    variants: Vec<RelaxVariant>,
+    current_variant: usize,
+}
+impl Relaxable {
+    /// the same relaxable, but with its index refs moved from the buffer with id `old_buf_id` to the one with id `buf_id`.
+    pub(crate) fn rebind(&self, old_buf_id: u64, buf_id: u64) -> Self {
+        Self {
+            region: self.region.rebind(old_buf_id, buf_id),
+            target: self.target.rebind(old_buf_id, buf_id),
+            ..self.clone()
+        }
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// inserts a relaxable instruction at the given index, initially using its first variant, and returns a range ref to
+    /// its encoding. the target is usually an index ref, but can be any expression.
+    /// the variants must be ordered from the shortest to the longest, and `relax` will grow the instruction through them
+    /// until its displacement to the target fits.
+    /// content inserted exactly at either boundary of the encoding stays outside of it, while edits inside of the encoding
+    /// are overwritten by the encoding of the instruction when it is relaxed.
+    pub fn add_relaxable(
+        &mut self,
+        index: usize,
+        target: impl Into<Expr>,
+        variants: Vec<RelaxVariant>,
+    ) -> IndexRangeRef {
+        unwrap_or_panic(self.try_add_relaxable(index, target, variants))
+    }
+    /// like `add_relaxable`, but returns an error instead of panicking.
+    pub fn try_add_relaxable(
+        &mut self,
+        index: usize,
+        target: impl Into<Expr>,
+        variants: Vec<RelaxVariant>,
+    ) -> Result<IndexRangeRef, Error> {
+        if variants.is_empty()
+            || variants
+                .windows(2)
+                .any(|pair| pair[0].len() > pair[1].len())
+        {
+            return Err(Error::InvalidRelaxVariants);
+        }
+        self.check_insertion_index(index)?;
+        let target = target.into();
+        self.try_insert_slice(index, &variants[0].template)?;
+        let region = self.try_create_range_ref_with_gravity(
+            index..index + variants[0].len(),
+            Gravity::Right,
+            Gravity::Left,
+        )?;
+        self.relaxables.push(Relaxable {
+            region,
+            target,
+            variants,
+            current_variant: 0,
+        });
+        Ok(region)
+    }
+    /// grows relaxable instructions whose displacement doesn't fit in their current variant, until the layout reaches a
+    /// fixed point, and then writes the final encoding of every relaxable instruction.
+    /// the targets of all instructions are re-evaluated in every round, since growing one instruction may change them.
+    ///
+    /// instructions are only ever grown, never shrunk, and every growth moves an instruction to a later variant, so the
+    /// number of rounds is bounded by the total number of variants, which guarantees termination.
+    /// an error is returned for every instruction whose displacement doesn't fit even in its last variant, or whose index
+    /// refs are no longer valid.
+    pub fn relax(&mut self) -> Result<(), Vec<Error>> {
+        self.composite_edit(|buf| {
+            while buf.relax_round() {}
+            let errors = buf.write_relaxables();
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        })
+    }
+    /// the current variant index of every relaxable instruction, in the order in which they were added.
+    pub fn relaxable_variants(&self) -> Vec<usize> {
+        self.relaxables
+            .iter()
+            .map(|relaxable| relaxable.current_variant)
+            .collect()
+    }
+    /// grows every relaxable instruction whose displacement doesn't fit in its current variant to the shortest variant
+    /// that currently fits, or to the last variant if none of them fit. returns whether any instruction was grown.
+    pub(crate) fn relax_round(&mut self) -> bool {
+        let mut grown = false;
+        for relaxable_index in 0..self.relaxables.len() {
+            let relaxable = &self.relaxables[relaxable_index];
+            let (Ok(region), Ok(target)) = (
+                self.try_read_range_ref(relaxable.region),
+                self.evaluate(&relaxable.target),
+            ) else {
+                continue;
+            };
+            let current_variant = relaxable.current_variant;
+            if region.len() != relaxable.variants[current_variant].len() {
+                // the encoding was edited from the outside, so its template is restored before measuring it.
+                let template = relaxable.variants[current_variant].template.clone();
+                self.splice(region, template);
+                grown = true;
+                continue;
+            }
+            if relaxable.variants[current_variant]
+                .encode(region.start, target)
+                .is_some()
+            {
+                continue;
+            }
+            let last_variant = relaxable.variants.len() - 1;
+            if current_variant == last_variant {
+                continue;
+            }
+            // the target may move while growing, but it will be checked again in the next round.
+            let new_variant = (current_variant + 1..last_variant)
+                .find(|&variant| {
+                    relaxable.variants[variant]
+                        .encode(region.start, target)
+                        .is_some()
+                })
+                .unwrap_or(last_variant);
+            let template = relaxable.variants[new_variant].template.clone();
+            self.relaxables[relaxable_index].current_variant = new_variant;
+            self.splice(region, template);
+            grown = true;
+        }
+        grown
+    }
+    /// writes the final encoding of every relaxable instruction, returning an error for every instruction that could not be
+    /// written.
+    pub(crate) fn write_relaxables(&mut self) -> Vec<Error> {
+        let mut errors = Vec::new();
+        for relaxable_index in 0..self.relaxables.len() {
+            if let Err(err) = self.write_relaxable(relaxable_index) {
+                errors.push(err);
+            }
+        }
+        errors
+    }
+    /// writes the final encoding of a relaxable instruction.
+    fn write_relaxable(&mut self, relaxable_index: usize) -> Result<(), Error> {
+        let relaxable = &self.relaxables[relaxable_index];
+        let region = self.try_read_range_ref(relaxable.region)?;
+        let target = self.evaluate(&relaxable.target)?;
+        let variant = &relaxable.variants[relaxable.current_variant];
+        let site = region.start + variant.field_offset;
+        let value = variant
+            .kind
+            .value(site, target, variant.addend)
+            .ok_or(Error::ArithmeticOverflow)?;
+        let bytes = variant
+            .encode(region.start, target)
+            .ok_or(Error::FixupOverflow {
+                site,
+                value,
+                width: variant.width,
+            })?;
+        if region.len() == bytes.len() {
+            self.buf[region].copy_from_slice(&bytes);
+        } else {
+            self.try_splice(region, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+pub fn make_sure_relaxation_grows_branches_until_they_fit() {
+    let jmp = || {
+        vec![
+            RelaxVariant::new(vec![0xeb, 0], 1, 1, Endianness::Little, FixupKind::Relative)
+                .with_addend(-1),
+            RelaxVariant::new(
+                vec![0xe9, 0, 0, 0, 0],
+                1,
+                4,
+                Endianness::Little,
+                FixupKind::Relative,
+            )
+            .with_addend(-4),
+        ]
+    };
+    let mut buf = IndexRefBuf::new();
+    let end = buf.create_index_ref_with_gravity(0, Gravity::End);
+    let first = buf.add_relaxable(0, end, jmp());
+    let after_first = buf.create_index_ref(buf.len());
+    buf.insert_slice(0, &[0x90; 123]);
+    // the second jump just fits, until the first one grows and pushes its target away.
+    let second = buf.add_relaxable(0, after_first, jmp());
+    buf.extend_from_slice(&[0x90; 200]);
+    let first_field = buf.create_index_ref(buf.read_range_ref(first).start + 1);
+    let second_field = buf.create_index_ref(buf.read_range_ref(second).start + 1);
+
+    buf.relax().unwrap();
+    assert_eq!(buf.relaxable_variants(), vec![1, 1]);
+    // the refs invalidated by growing each of the jumps are all reported.
+    assert_eq!(buf.invalidated_refs().len(), 2);
+    assert!(buf.invalidated_refs().contains(&first_field));
+    assert!(buf.invalidated_refs().contains(&second_field));
+    assert_eq!(buf.read_range_ref(first), 128..133);
+    assert_eq!(buf.range_ref_slice(first), &[0xe9, 200, 0, 0, 0]);
+    assert_eq!(buf.range_ref_slice(second), &[0xe9, 128, 0, 0, 0]);
+    assert_eq!(buf.len(), 333);
+}
+
+#[test]
+pub fn make_sure_expression_sizes_are_re_evaluated() {
+    // a length field covering itself and the body after it, which needs to grow once the body becomes large.
+    let length = || {
+        vec![
+            RelaxVariant::new(vec![0], 0, 1, Endianness::Little, FixupKind::Absolute),
+            RelaxVariant::new(
+                vec![0xff, 0, 0],
+                1,
+                2,
+                Endianness::Little,
+                FixupKind::Absolute,
+            ),
+        ]
+    };
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 254]);
+    let start = buf.create_index_ref_with_gravity(0, Gravity::Left);
+    let end = buf.create_index_ref_with_gravity(254, Gravity::End);
+    buf.add_relaxable(0, Expr::from(end) - start, length());
+    buf.finalize().unwrap();
+    assert_eq!(buf[0], 255);
+
+    buf.push(0);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..3], &[0xff, 2, 1]);
+}
+
+#[test]
+pub fn make_sure_edits_inside_of_relaxables_are_overwritten() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xc3]);
+    let target = buf.create_index_ref_with_gravity(1, Gravity::End);
+    let jmp = buf.add_relaxable(
+        0,
+        target,
+        vec![
+            RelaxVariant::new(vec![0xeb, 0], 1, 1, Endianness::Little, FixupKind::Relative)
+                .with_addend(-1),
+        ],
+    );
+    buf.insert(1, 0x90);
+    buf.finalize().unwrap();
+    assert_eq!(buf.read_range_ref(jmp), 0..2);
+    assert_eq!(&buf[..], &[0xeb, 1, 0xc3]);
+}
+
+#[test]
+pub fn make_sure_invalid_variants_are_rejected() {
+    assert_eq!(
+        RelaxVariant::try_new(vec![0xeb, 0], 1, 9, Endianness::Little, FixupKind::Relative),
+        Err(Error::InvalidWidth { width: 9 })
+    );
+    assert_eq!(
+        RelaxVariant::try_new(vec![0xeb, 0], 1, 2, Endianness::Little, FixupKind::Relative),
+        Err(Error::InvalidRelaxVariants)
+    );
+}
@@ -0,0 +1,518 @@
+//! multi-section buffers, whose sections are built independently and then laid out one after another in an image.
+
+use crate::{
+    alloc_buf_id,
+    fixup::{check_width, encode_int},
+    unwrap_or_panic, Endianness, Error, FixupKind, Gravity, IndexRef, IndexRefBuf,
+};
+
+/// the maximal number of rounds of finalizing the sections at their image addresses, before the layout is considered to
+/// be oscillating.
+const MAX_LAYOUT_ROUNDS: usize = 64;
+
+/// a handle to a section of a sectioned buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Section {
+    buf_id: u64,
+    section_index: usize,
+}
+
+/// an index ref into a specific section of a sectioned buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectionRef {
+    section: Section,
+    index_ref: IndexRef,
+}
+impl SectionRef {
+    /// the section that the index ref points into.
+    pub fn section(&self) -> Section {
+        self.section
+    }
+    /// the index ref in the buffer of the section.
+    pub fn index_ref(&self) -> IndexRef {
+        self.index_ref
+    }
+}
+
+/// a fixup whose patch site and target may be in different sections, which is written when laying out the sections.
+/// the value which is written is computed like the value of a `Fixup`, where the value of the target is its virtual
+/// address in the image, and the distance of relative fixups is computed between image offsets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SectionFixup {
+    site: SectionRef,
+    target: SectionRef,
+    width: usize,
+    endianness: Endianness,
+    kind: FixupKind,
+    addend: i64,
+}
+impl SectionFixup {
+    /// creates a fixup of the given kind. the width is in bytes, and must be between 1 and 8.
+    pub fn new(
+        site: SectionRef,
+        target: SectionRef,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Self {
+        unwrap_or_panic(Self::try_new(site, target, width, endianness, kind))
+    }
+    /// like `new`, but returns an error instead of panicking.
+    pub fn try_new(
+        site: SectionRef,
+        target: SectionRef,
+        width: usize,
+        endianness: Endianness,
+        kind: FixupKind,
+    ) -> Result<Self, Error> {
+        check_width(width)?;
+        Ok(Self {
+            site,
+            target,
+            width,
+            endianness,
+            kind,
+            addend: 0,
+        })
+    }
+    /// adds the given addend to the value of the fixup.
+    pub fn with_addend(self, addend: i64) -> Self {
+        Self { addend, ..self }
+    }
+    /// the patch site, where the value is written.
+    pub fn site(&self) -> SectionRef {
+        self.site
+    }
+    /// the target of the fixup.
+    pub fn target(&self) -> SectionRef {
+        self.target
+    }
+}
+
+/// the image offsets of the sections of a sectioned buffer, computed by `SectionedBuf::layout`.
+///
+/// a layout becomes stale once sections are added to the buffer or change their length, and using a stale layout with
+/// the buffer returns an error.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Layout {
+    buf_id: u64,
+    section_offsets: Vec<usize>,
+    /// the lengths of the sections when the layout was computed, used to detect stale layouts.
+    section_lens: Vec<usize>,
+    len: usize,
+}
+impl Layout {
+    /// the image offset of the start of the given section.
+    pub fn section_offset(&self, section: Section) -> usize {
+        unwrap_or_panic(self.try_section_offset(section))
+    }
+    /// like `section_offset`, but returns an error instead of panicking.
+    pub fn try_section_offset(&self, section: Section) -> Result<usize, Error> {
+        if section.buf_id != self.buf_id {
+            return Err(Error::ForeignRef);
+        }
+        self.section_offsets
+            .get(section.section_index)
+            .copied()
+            .ok_or(Error::StaleLayout)
+    }
+    /// the length of the image, including the padding between sections.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    /// checks if the image is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// a section of a sectioned buffer.
+#[derive(Debug)]
+struct SectionEntry {
+    name: String,
+    align: usize,
+    buf: IndexRefBuf<u8>,
+}
+
+/// a buffer made of several named sections, which are built independently, and are then laid out one after another in
+/// the order in which they were added, each one aligned to its own alignment.
+///
+/// the alignment of a section is relative to the start of the image, so alignment directives inside of a section are
+/// only correct in the image if the section is at least as aligned as them.
+#[derive(Debug)]
+pub struct SectionedBuf {
+    id: u64,
+    sections: Vec<SectionEntry>,
+    fixups: Vec<SectionFixup>,
+    load_base: u64,
+}
+impl SectionedBuf {
+    /// creates a new sectioned buffer without any sections.
+    pub fn new() -> Self {
+        Self {
+            id: alloc_buf_id(),
+            sections: Vec::new(),
+            fixups: Vec::new(),
+            load_base: 0,
+        }
+    }
+    /// the load base of the image, which is the virtual address of its start.
+    pub fn load_base(&self) -> u64 {
+        self.load_base
+    }
+    /// sets the load base of the image, which is the virtual address of its start.
+    /// the load bases of the sections themselves are overwritten by `layout` with the virtual addresses of their starts.
+    pub fn set_load_base(&mut self, load_base: u64) {
+        self.load_base = load_base;
+    }
+    /// adds an empty section with the given name and alignment after all existing sections.
+    pub fn add_section(&mut self, name: &str, align: usize) -> Section {
+        unwrap_or_panic(self.try_add_section(name, align))
+    }
+    /// like `add_section`, but returns an error instead of panicking.
+    pub fn try_add_section(&mut self, name: &str, align: usize) -> Result<Section, Error> {
+        if align == 0 {
+            return Err(Error::InvalidAlignment);
+        }
+        self.sections.push(SectionEntry {
+            name: name.to_string(),
+            align,
+            buf: IndexRefBuf::new(),
+        });
+        Ok(Section {
+            buf_id: self.id,
+            section_index: self.sections.len() - 1,
+        })
+    }
+    /// the first section with the given name, if any.
+    pub fn section_by_name(&self, name: &str) -> Option<Section> {
+        self.sections
+            .iter()
+            .position(|entry| entry.name == name)
+            .map(|section_index| Section {
+                buf_id: self.id,
+                section_index,
+            })
+    }
+    /// all sections, in the order in which they are laid out.
+    pub fn sections(&self) -> impl Iterator<Item = Section> + '_ {
+        (0..self.sections.len()).map(|section_index| Section {
+            buf_id: self.id,
+            section_index,
+        })
+    }
+    /// the name of the given section.
+    pub fn section_name(&self, section: Section) -> &str {
+        &self.entry(section).name
+    }
+    /// the alignment of the given section.
+    pub fn section_align(&self, section: Section) -> usize {
+        self.entry(section).align
+    }
+    /// the buffer of the given section.
+    pub fn section(&self, section: Section) -> &IndexRefBuf<u8> {
+        &self.entry(section).buf
+    }
+    /// the buffer of the given section, which can be edited like any other buffer.
+    pub fn section_mut(&mut self, section: Section) -> &mut IndexRefBuf<u8> {
+        unwrap_or_panic(self.check_section(section));
+        &mut self.sections[section.section_index].buf
+    }
+    /// creates an index ref to the given index in the given section.
+    pub fn create_ref(&mut self, section: Section, index: usize) -> SectionRef {
+        self.create_ref_with_gravity(section, index, Gravity::default())
+    }
+    /// creates an index ref with the given gravity to the given index in the given section.
+    pub fn create_ref_with_gravity(
+        &mut self,
+        section: Section,
+        index: usize,
+        gravity: Gravity,
+    ) -> SectionRef {
+        unwrap_or_panic(self.try_create_ref_with_gravity(section, index, gravity))
+    }
+    /// like `create_ref_with_gravity`, but returns an error instead of panicking.
+    pub fn try_create_ref_with_gravity(
+        &mut self,
+        section: Section,
+        index: usize,
+        gravity: Gravity,
+    ) -> Result<SectionRef, Error> {
+        self.check_section(section)?;
+        let index_ref = self.sections[section.section_index]
+            .buf
+            .try_create_index_ref_with_gravity(index, gravity)?;
+        Ok(SectionRef { section, index_ref })
+    }
+    /// reads the index of the given section ref, relative to the start of its section.
+    pub fn read_ref(&self, section_ref: SectionRef) -> usize {
+        unwrap_or_panic(self.try_read_ref(section_ref))
+    }
+    /// like `read_ref`, but returns an error instead of panicking.
+    pub fn try_read_ref(&self, section_ref: SectionRef) -> Result<usize, Error> {
+        self.check_section(section_ref.section)?;
+        self.sections[section_ref.section.section_index]
+            .buf
+            .try_read_index_ref(section_ref.index_ref)
+    }
+    /// registers a fixup, which will be written by `layout`.
+    pub fn add_fixup(&mut self, fixup: SectionFixup) {
+        self.fixups.push(fixup);
+    }
+    /// the registered fixups.
+    pub fn fixups(&self) -> &[SectionFixup] {
+        &self.fixups
+    }
+    /// lays out the sections one after another, and returns their image offsets.
+    ///
+    /// every section is first finalized on its own, which fixes its size, with its load base set to the virtual address
+    /// of its start in the image, so that addresses inside of the section are resolved against the image. since
+    /// addresses may change the sizes of the sections, for example through relaxation, the sections are finalized again
+    /// until their image offsets stop changing. then, the sections are placed at their alignment, the fixups between
+    /// sections are written using the image offsets of their patch sites and targets, and finally the checksums of every
+    /// section are computed again, since the fixups may have changed their content.
+    /// every step runs even if a previous one failed, and the errors of all steps are returned together.
+    pub fn layout(&mut self) -> Result<Layout, Vec<Error>> {
+        let mut errors;
+        let (mut section_offsets, mut len) = self.section_offsets();
+        let mut rounds = 0;
+        loop {
+            errors = Vec::new();
+            for (entry, &offset) in self.sections.iter_mut().zip(&section_offsets) {
+                entry
+                    .buf
+                    .set_load_base(self.load_base.wrapping_add(offset as u64));
+                if let Err(section_errors) = entry.buf.finalize() {
+                    errors.extend(section_errors);
+                }
+            }
+            let (new_section_offsets, new_len) = self.section_offsets();
+            if new_section_offsets == section_offsets {
+                break;
+            }
+            (section_offsets, len) = (new_section_offsets, new_len);
+            rounds += 1;
+            if rounds == MAX_LAYOUT_ROUNDS {
+                errors.push(Error::LayoutDidNotConverge);
+                break;
+            }
+        }
+        let layout = Layout {
+            buf_id: self.id,
+            section_offsets,
+            section_lens: self.sections.iter().map(|entry| entry.buf.len()).collect(),
+            len,
+        };
+
+        for fixup_index in 0..self.fixups.len() {
+            if let Err(err) = self.resolve_fixup(&layout, fixup_index) {
+                errors.push(err);
+            }
+        }
+        for entry in &mut self.sections {
+            if let Err(checksum_errors) = entry.buf.write_checksums() {
+                errors.extend(checksum_errors);
+            }
+        }
+        if errors.is_empty() {
+            Ok(layout)
+        } else {
+            Err(errors)
+        }
+    }
+    /// the image offset of the given section ref according to the given layout.
+    pub fn image_offset(&self, layout: &Layout, section_ref: SectionRef) -> usize {
+        unwrap_or_panic(self.try_image_offset(layout, section_ref))
+    }
+    /// like `image_offset`, but returns an error instead of panicking.
+    pub fn try_image_offset(
+        &self,
+        layout: &Layout,
+        section_ref: SectionRef,
+    ) -> Result<usize, Error> {
+        self.check_layout(layout)?;
+        let index = self.try_read_ref(section_ref)?;
+        Ok(layout.section_offsets[section_ref.section.section_index] + index)
+    }
+    /// the virtual address of the given section ref according to the given layout, which is its image offset plus the
+    /// load base.
+    pub fn image_address(&self, layout: &Layout, section_ref: SectionRef) -> u64 {
+        unwrap_or_panic(self.try_image_address(layout, section_ref))
+    }
+    /// like `image_address`, but returns an error instead of panicking.
+    pub fn try_image_address(
+        &self,
+        layout: &Layout,
+        section_ref: SectionRef,
+    ) -> Result<u64, Error> {
+        let offset = self.try_image_offset(layout, section_ref)?;
+        Ok(self.load_base.wrapping_add(offset as u64))
+    }
+    /// concatenates the content of the sections according to the given layout, filling the gaps between them with zeros.
+    pub fn image(&self, layout: &Layout) -> Vec<u8> {
+        unwrap_or_panic(self.try_image(layout))
+    }
+    /// like `image`, but returns an error instead of panicking.
+    pub fn try_image(&self, layout: &Layout) -> Result<Vec<u8>, Error> {
+        self.check_layout(layout)?;
+        let mut image = vec![0; layout.len];
+        for (entry, &offset) in self.sections.iter().zip(&layout.section_offsets) {
+            image[offset..offset + entry.buf.len()].copy_from_slice(&entry.buf);
+        }
+        Ok(image)
+    }
+    /// checks that the given layout was computed by this buffer, and that no section was added or resized since.
+    fn check_layout(&self, layout: &Layout) -> Result<(), Error> {
+        if layout.buf_id != self.id {
+            return Err(Error::ForeignRef);
+        }
+        let section_lens = self.sections.iter().map(|entry| entry.buf.len());
+        if !section_lens.eq(layout.section_lens.iter().copied()) {
+            return Err(Error::StaleLayout);
+        }
+        Ok(())
+    }
+    /// the image offset of every section when placing them one after another at their alignment, and the length of the
+    /// image.
+    fn section_offsets(&self) -> (Vec<usize>, usize) {
+        let mut section_offsets = Vec::new();
+        let mut len = 0usize;
+        for entry in &self.sections {
+            len = len.next_multiple_of(entry.align);
+            section_offsets.push(len);
+            len += entry.buf.len();
+        }
+        (section_offsets, len)
+    }
+    /// the given section, panicking if it belongs to a different buffer.
+    fn entry(&self, section: Section) -> &SectionEntry {
+        unwrap_or_panic(self.check_section(section));
+        &self.sections[section.section_index]
+    }
+    /// checks that the given section belongs to this buffer.
+    fn check_section(&self, section: Section) -> Result<(), Error> {
+        if section.buf_id != self.id {
+            return Err(Error::ForeignRef);
+        }
+        Ok(())
+    }
+    /// writes the value of a single fixup into the section of its patch site.
+    fn resolve_fixup(&mut self, layout: &Layout, fixup_index: usize) -> Result<(), Error> {
+        let fixup = self.fixups[fixup_index];
+        let site = self.try_image_offset(layout, fixup.site)?;
+        let target = match fixup.kind {
+            FixupKind::Relative => self.try_image_offset(layout, fixup.target)? as i128,
+            FixupKind::Absolute | FixupKind::SignedAbsolute => {
+                self.try_image_address(layout, fixup.target)? as i128
+            }
+        };
+        let value = fixup
+            .kind
+            .value(site, target, fixup.addend)
+            .ok_or(Error::ArithmeticOverflow)?;
+        let bytes = encode_int(value, fixup.width, fixup.endianness, fixup.kind.is_signed())
+            .ok_or(Error::FixupOverflow {
+                site,
+                value,
+                width: fixup.width,
+            })?;
+        let index = self.try_read_ref(fixup.site)?;
+        let section_buf = &mut self.sections[fixup.site.section.section_index].buf;
+        let (start, end) = section_buf.try_range_indices(&(index..index + fixup.width))?;
+        section_buf.buf[start..end].copy_from_slice(&bytes);
+        Ok(())
+    }
+}
+impl Default for SectionedBuf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+pub fn make_sure_sections_are_laid_out_with_cross_section_fixups() {
+    let mut image = SectionedBuf::new();
+    let text = image.add_section(".text", 16);
+    let data = image.add_section(".data", 16);
+    assert_eq!(image.section_by_name(".data"), Some(data));
+
+    // `lea rax, [rip + value]` in .text, and a pointer to the lea in .data.
+    image
+        .section_mut(text)
+        .extend_from_slice(&[0x48, 0x8d, 0x05, 0, 0, 0, 0]);
+    let disp = image.create_ref(text, 3);
+    let lea = image.create_ref(text, 0);
+    image.section_mut(data).extend_from_slice(&[0, 0, 0, 0, 42]);
+    let pointer = image.create_ref(data, 0);
+    let value = image.create_ref(data, 4);
+    image.add_fixup(
+        SectionFixup::new(disp, value, 4, Endianness::Little, FixupKind::Relative).with_addend(-4),
+    );
+    image.add_fixup(SectionFixup::new(
+        pointer,
+        lea,
+        4,
+        Endianness::Little,
+        FixupKind::Absolute,
+    ));
+
+    // growing .text after the references were created moves .data and the lea.
+    image.section_mut(text).insert_slice(0, &[0x90; 10]);
+    let layout = image.layout().unwrap();
+    assert_eq!(layout.section_offset(data), 32);
+    assert_eq!(image.image_offset(&layout, value), 36);
+    let bytes = image.image(&layout);
+    assert_eq!(bytes.len(), 37);
+    assert_eq!(&bytes[13..17], &(36i32 - 17).to_le_bytes());
+    assert_eq!(&bytes[17..32], &[0; 15]);
+    assert_eq!(&bytes[32..], &[10, 0, 0, 0, 42]);
+}
+
+#[test]
+pub fn make_sure_stale_layouts_are_detected() {
+    let mut image = SectionedBuf::new();
+    let text = image.add_section(".text", 16);
+    image.section_mut(text).extend_from_slice(&[0x90; 4]);
+    let nop = image.create_ref(text, 2);
+    let layout = image.layout().unwrap();
+    assert_eq!(image.image_offset(&layout, nop), 2);
+    assert_eq!(
+        SectionFixup::try_new(nop, nop, 0, Endianness::Little, FixupKind::Absolute),
+        Err(Error::InvalidWidth { width: 0 })
+    );
+
+    let data = image.add_section(".data", 16);
+    let value = image.create_ref(data, 0);
+    assert_eq!(layout.try_section_offset(data), Err(Error::StaleLayout));
+    assert_eq!(
+        image.try_image_offset(&layout, value),
+        Err(Error::StaleLayout)
+    );
+
+    let layout = image.layout().unwrap();
+    image.section_mut(text).insert(0, 0xcc);
+    assert_eq!(
+        image.try_image_offset(&layout, nop),
+        Err(Error::StaleLayout)
+    );
+    assert_eq!(image.try_image(&layout), Err(Error::StaleLayout));
+}
+
+#[test]
+pub fn make_sure_addresses_inside_of_sections_use_the_image_address() {
+    use crate::Fixup;
+
+    let mut image = SectionedBuf::new();
+    image.set_load_base(0x400000);
+    let text = image.add_section(".text", 16);
+    let data = image.add_section(".data", 16);
+    image.section_mut(text).extend_from_slice(&[0x90; 3]);
+    let data_buf = image.section_mut(data);
+    data_buf.extend_from_slice(&[0; 8]);
+    let (site, target) = (data_buf.create_index_ref(0), data_buf.create_index_ref(4));
+    data_buf.add_fixup(Fixup::address(site, target, 4, Endianness::Little));
+
+    let layout = image.layout().unwrap();
+    let bytes = image.image(&layout);
+    assert_eq!(&bytes[16..20], &0x400014u32.to_le_bytes());
+}
@@ -0,0 +1,173 @@
+//! a builder of nested tag-length-value encodings, such as ASN.1 DER, on top of length fields.
+
+use crate::{
+    fixup::check_width, unwrap_or_panic, Error, Gravity, IndexRangeRef, IndexRef, IndexRefBuf,
+    LengthEncoding, LengthField,
+};
+
+/// a tag-length-value element in a buffer, made of a tag, a length header, and a value which may contain nested
+/// elements. the length header is kept correct by `update_length_fields` and `finalize`, like any other length field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TlvElement {
+    tag: IndexRangeRef,
+    length: LengthField,
+}
+impl TlvElement {
+    /// the range ref of the tag.
+    pub fn tag(&self) -> IndexRangeRef {
+        self.tag
+    }
+    /// the length field which holds the length of the value.
+    pub fn length(&self) -> LengthField {
+        self.length
+    }
+    /// the range ref of the value.
+    pub fn value(&self) -> IndexRangeRef {
+        self.length.body()
+    }
+    /// the index ref to the start of the element, which is the start of its tag.
+    pub fn start(&self) -> IndexRef {
+        self.tag.start()
+    }
+    /// the index ref to the end of the element, which is the end of its value.
+    pub fn end(&self) -> IndexRef {
+        self.length.body().end()
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// inserts an element with the given tag and an empty value at the given index.
+    /// when the index is at the end of the value of an element, the new element becomes part of that element and of every
+    /// element nested in it that ends at the same index. use `append_tlv` to add an element as the last child of an
+    /// element.
+    pub fn create_tlv(&mut self, index: usize, tag: &[u8], encoding: LengthEncoding) -> TlvElement {
+        unwrap_or_panic(self.try_create_tlv(index, tag, encoding))
+    }
+    /// like `create_tlv`, but returns an error instead of panicking.
+    pub fn try_create_tlv(
+        &mut self,
+        index: usize,
+        tag: &[u8],
+        encoding: LengthEncoding,
+    ) -> Result<TlvElement, Error> {
+        check_encoding(encoding)?;
+        self.composite_edit(|buf| {
+            buf.try_insert_slice(index, tag)?;
+            buf.tlv_after_tag(index, tag.len(), encoding)
+        })
+    }
+    /// appends an element with the given tag and an empty value as the last child of the given parent element.
+    pub fn append_tlv(
+        &mut self,
+        parent: TlvElement,
+        tag: &[u8],
+        encoding: LengthEncoding,
+    ) -> TlvElement {
+        unwrap_or_panic(self.try_append_tlv(parent, tag, encoding))
+    }
+    /// like `append_tlv`, but returns an error instead of panicking.
+    pub fn try_append_tlv(
+        &mut self,
+        parent: TlvElement,
+        tag: &[u8],
+        encoding: LengthEncoding,
+    ) -> Result<TlvElement, Error> {
+        check_encoding(encoding)?;
+        self.composite_edit(|buf| {
+            let index = buf.try_read_index_ref(parent.end())?;
+            buf.try_append_to_length_field(parent.length, tag)?;
+            buf.tlv_after_tag(index, tag.len(), encoding)
+        })
+    }
+    /// appends the given bytes to the value of the given element, outside of any element nested in it.
+    pub fn append_to_tlv(&mut self, element: TlvElement, bytes: &[u8]) {
+        unwrap_or_panic(self.try_append_to_tlv(element, bytes))
+    }
+    /// like `append_to_tlv`, but returns an error instead of panicking.
+    pub fn try_append_to_tlv(&mut self, element: TlvElement, bytes: &[u8]) -> Result<(), Error> {
+        self.try_append_to_length_field(element.length, bytes)
+    }
+    /// creates an element whose tag was already inserted at the given index, by inserting its length header right after
+    /// the tag.
+    fn tlv_after_tag(
+        &mut self,
+        index: usize,
+        tag_len: usize,
+        encoding: LengthEncoding,
+    ) -> Result<TlvElement, Error> {
+        let tag = self.try_create_range_ref_with_gravity(
+            index..index + tag_len,
+            Gravity::Right,
+            Gravity::Left,
+        )?;
+        let value_start = index + tag_len;
+        let length = self.try_create_length_field(value_start..value_start, encoding)?;
+        Ok(TlvElement { tag, length })
+    }
+}
+
+/// checks that the given encoding is valid, so that an element is never left without a length header.
+fn check_encoding(encoding: LengthEncoding) -> Result<(), Error> {
+    if let LengthEncoding::Fixed { width, .. } = encoding {
+        check_width(width)?;
+    }
+    Ok(())
+}
+
+/// encodes the given length as a DER length.
+pub(crate) fn encode_der_length(len: usize) -> Vec<u8> {
+    if len < 0x80 {
+        return vec![len as u8];
+    }
+    let bytes = len.to_be_bytes();
+    let leading_zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    let mut encoded = vec![0x80 | (bytes.len() - leading_zeros) as u8];
+    encoded.extend_from_slice(&bytes[leading_zeros..]);
+    encoded
+}
+
+#[test]
+pub fn make_sure_nested_der_lengths_are_re_encoded() {
+    // SEQUENCE { INTEGER 5, SEQUENCE { OCTET STRING } }
+    let mut buf = IndexRefBuf::new();
+    let outer = buf.create_tlv(0, &[0x30], LengthEncoding::Der);
+    let integer = buf.append_tlv(outer, &[0x02], LengthEncoding::Der);
+    buf.append_to_tlv(integer, &[5]);
+    let inner = buf.append_tlv(outer, &[0x30], LengthEncoding::Der);
+    let octets = buf.append_tlv(inner, &[0x04], LengthEncoding::Der);
+    buf.finalize().unwrap();
+    assert_eq!(&buf[..], &[0x30, 7, 0x02, 1, 5, 0x30, 2, 0x04, 0]);
+
+    let integer_value = buf.create_index_ref(buf.read_range_ref(integer.value()).start);
+    buf.append_to_tlv(octets, &[0xaa; 200]);
+    buf.finalize().unwrap();
+    assert_eq!(
+        &buf[..12],
+        &[0x30, 0x81, 209, 0x02, 1, 5, 0x30, 0x81, 203, 0x04, 0x81, 200]
+    );
+    assert_eq!(buf[buf.read_index_ref(integer_value)], 5);
+    assert_eq!(buf.read_index_ref(outer.end()), buf.len());
+    assert_eq!(buf.read_range_ref(octets.value()), 12..212);
+}
+
+#[test]
+pub fn make_sure_invalid_encodings_leave_the_buffer_unchanged() {
+    use crate::Endianness;
+
+    let mut buf = IndexRefBuf::new();
+    let outer = buf.create_tlv(0, &[0x30], LengthEncoding::Der);
+    let before = buf.clone();
+    let invalid = LengthEncoding::Fixed {
+        width: 0,
+        endianness: Endianness::Big,
+    };
+    assert_eq!(
+        buf.try_create_tlv(0, &[0x02], invalid),
+        Err(Error::InvalidWidth { width: 0 })
+    );
+    assert_eq!(
+        buf.try_append_tlv(outer, &[0x02], invalid),
+        Err(Error::InvalidWidth { width: 0 })
+    );
+    assert_eq!(buf, before);
+}
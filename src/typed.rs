@@ -0,0 +1,174 @@
+//! in place reads and writes of typed values, which never shift the content of the buffer or move its index refs.
+
+use crate::{unwrap_or_panic, Endianness, Error, IndexRef, IndexRefBuf};
+
+/// a position in a buffer, which is either a plain index or an index ref.
+pub trait BufIndex {
+    /// the index of the position in the given buffer.
+    fn resolve<T>(self, buf: &IndexRefBuf<T>) -> Result<usize, Error>;
+}
+impl BufIndex for usize {
+    fn resolve<T>(self, _buf: &IndexRefBuf<T>) -> Result<usize, Error> {
+        Ok(self)
+    }
+}
+impl BufIndex for IndexRef {
+    fn resolve<T>(self, buf: &IndexRefBuf<T>) -> Result<usize, Error> {
+        buf.try_read_index_ref(self)
+    }
+}
+
+/// a value with a fixed size byte representation, like the primitive integer and floating point types.
+pub trait ByteRepr: Sized {
+    /// the size of the representation, in bytes.
+    const SIZE: usize;
+    /// writes the representation of the value into the given bytes, whose length is `SIZE`.
+    fn write_bytes(&self, bytes: &mut [u8], endianness: Endianness);
+    /// reads a value from the given bytes, whose length is `SIZE`.
+    fn read_bytes(bytes: &[u8], endianness: Endianness) -> Self;
+}
+macro_rules! impl_byte_repr {
+    ($($ty:ty),*) => {
+        $(
+            impl ByteRepr for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn write_bytes(&self, bytes: &mut [u8], endianness: Endianness) {
+                    bytes.copy_from_slice(&match endianness {
+                        Endianness::Little => self.to_le_bytes(),
+                        Endianness::Big => self.to_be_bytes(),
+                    });
+                }
+                fn read_bytes(bytes: &[u8], endianness: Endianness) -> Self {
+                    let bytes = bytes.try_into().unwrap();
+                    match endianness {
+                        Endianness::Little => <$ty>::from_le_bytes(bytes),
+                        Endianness::Big => <$ty>::from_be_bytes(bytes),
+                    }
+                }
+            }
+        )*
+    };
+}
+impl_byte_repr!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl<T: Clone> IndexRefBuf<T> {
+    /// overwrites the elements at the given position with the given elements, without shifting the rest of the buffer or
+    /// moving any index refs.
+    pub fn overwrite(&mut self, at: impl BufIndex, elements: &[T]) {
+        unwrap_or_panic(self.try_overwrite(at, elements))
+    }
+    /// like `overwrite`, but returns an error instead of panicking.
+    pub fn try_overwrite(&mut self, at: impl BufIndex, elements: &[T]) -> Result<(), Error> {
+        let (start, end) = self.try_span(at, elements.len())?;
+        self.buf[start..end].clone_from_slice(elements);
+        Ok(())
+    }
+}
+
+impl<T> IndexRefBuf<T> {
+    /// the bounds of the span of the given length at the given position, checking that it is inside of the buffer.
+    fn try_span(&self, at: impl BufIndex, len: usize) -> Result<(usize, usize), Error> {
+        let start = at.resolve(self)?;
+        self.try_range_indices(&(start..start.saturating_add(len)))
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// writes the given value in place at the given position.
+    pub fn write<V: ByteRepr>(&mut self, at: impl BufIndex, value: V, endianness: Endianness) {
+        unwrap_or_panic(self.try_write(at, value, endianness))
+    }
+    /// like `write`, but returns an error instead of panicking.
+    pub fn try_write<V: ByteRepr>(
+        &mut self,
+        at: impl BufIndex,
+        value: V,
+        endianness: Endianness,
+    ) -> Result<(), Error> {
+        let (start, end) = self.try_span(at, V::SIZE)?;
+        value.write_bytes(&mut self.buf[start..end], endianness);
+        Ok(())
+    }
+    /// reads a value at the given position.
+    pub fn read<V: ByteRepr>(&self, at: impl BufIndex, endianness: Endianness) -> V {
+        unwrap_or_panic(self.try_read(at, endianness))
+    }
+    /// like `read`, but returns an error instead of panicking.
+    pub fn try_read<V: ByteRepr>(
+        &self,
+        at: impl BufIndex,
+        endianness: Endianness,
+    ) -> Result<V, Error> {
+        let (start, end) = self.try_span(at, V::SIZE)?;
+        Ok(V::read_bytes(&self.buf[start..end], endianness))
+    }
+}
+
+macro_rules! impl_typed_accessors {
+    ($($ty:ty, $endianness:ident, $write:ident, $try_write:ident, $read:ident, $try_read:ident;)*) => {
+        impl IndexRefBuf<u8> {
+            $(
+                #[doc = concat!("writes a `", stringify!($ty), "` in place at the given position.")]
+                pub fn $write(&mut self, at: impl BufIndex, value: $ty) {
+                    self.write(at, value, Endianness::$endianness)
+                }
+                #[doc = concat!("like `", stringify!($write), "`, but returns an error instead of panicking.")]
+                pub fn $try_write(&mut self, at: impl BufIndex, value: $ty) -> Result<(), Error> {
+                    self.try_write(at, value, Endianness::$endianness)
+                }
+                #[doc = concat!("reads a `", stringify!($ty), "` at the given position.")]
+                pub fn $read(&self, at: impl BufIndex) -> $ty {
+                    self.read(at, Endianness::$endianness)
+                }
+                #[doc = concat!("like `", stringify!($read), "`, but returns an error instead of panicking.")]
+                pub fn $try_read(&self, at: impl BufIndex) -> Result<$ty, Error> {
+                    self.try_read(at, Endianness::$endianness)
+                }
+            )*
+        }
+    };
+}
+impl_typed_accessors! {
+    u8, Little, write_u8, try_write_u8, read_u8, try_read_u8;
+    i8, Little, write_i8, try_write_i8, read_i8, try_read_i8;
+    u16, Little, write_u16_le, try_write_u16_le, read_u16_le, try_read_u16_le;
+    u16, Big, write_u16_be, try_write_u16_be, read_u16_be, try_read_u16_be;
+    i16, Little, write_i16_le, try_write_i16_le, read_i16_le, try_read_i16_le;
+    i16, Big, write_i16_be, try_write_i16_be, read_i16_be, try_read_i16_be;
+    u32, Little, write_u32_le, try_write_u32_le, read_u32_le, try_read_u32_le;
+    u32, Big, write_u32_be, try_write_u32_be, read_u32_be, try_read_u32_be;
+    i32, Little, write_i32_le, try_write_i32_le, read_i32_le, try_read_i32_le;
+    i32, Big, write_i32_be, try_write_i32_be, read_i32_be, try_read_i32_be;
+    u64, Little, write_u64_le, try_write_u64_le, read_u64_le, try_read_u64_le;
+    u64, Big, write_u64_be, try_write_u64_be, read_u64_be, try_read_u64_be;
+    i64, Little, write_i64_le, try_write_i64_le, read_i64_le, try_read_i64_le;
+    i64, Big, write_i64_be, try_write_i64_be, read_i64_be, try_read_i64_be;
+}
+
+#[test]
+pub fn make_sure_typed_writes_are_in_place_and_bounds_checked() {
+    let mut buf = IndexRefBuf::from_vec(vec![0u8; 8]);
+    let field = buf.create_index_ref(2);
+    let inside = buf.create_index_ref(3);
+    buf.write_u32_be(field, 0x11223344);
+    buf.write_u16_le(6, 0xbeef);
+    assert_eq!(&buf[..], &[0, 0, 0x11, 0x22, 0x33, 0x44, 0xef, 0xbe]);
+    assert_eq!(buf.read_index_ref(inside), 3);
+    assert_eq!(buf.read_i16_le(6), 0xbeefu16 as i16);
+    assert_eq!(
+        buf.read::<f32>(field, Endianness::Big),
+        f32::from_bits(0x11223344)
+    );
+
+    assert_eq!(
+        buf.try_write_i64_le(field, -1),
+        Err(Error::InvalidRange {
+            start: 2,
+            end: 10,
+            len: 8
+        })
+    );
+    assert!(buf.try_read_u16_be(usize::MAX).is_err());
+    assert_eq!(buf.read_u32_be(field), 0x11223344);
+}
@@ -0,0 +1,247 @@
+//! LEB128 variable length integers in the buffer, including patchable fields whose width changes with their value.
+
+use crate::{
+    leb128::{
+        decode_sleb128, decode_uleb128, encode_sleb128, encode_uleb128, pad_leb128, MAX_LEB128_LEN,
+    },
+    unwrap_or_panic, BufIndex, Error, Gravity, IndexRangeRef, IndexRefBuf,
+};
+
+/// the encoding of a varint field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VarintEncoding {
+    /// a minimal unsigned LEB128 integer.
+    Uleb128,
+    /// a minimal signed LEB128 integer.
+    Sleb128,
+    /// an unsigned LEB128 integer padded to the given width in bytes, so that it can be patched without resizing it. the
+    /// width must be between 1 and 18 bytes.
+    PaddedUleb128(usize),
+    /// a signed LEB128 integer padded to the given width in bytes, so that it can be patched without resizing it. the
+    /// width must be between 1 and 18 bytes.
+    PaddedSleb128(usize),
+}
+impl VarintEncoding {
+    /// encodes the given value, or returns `None` if it doesn't fit.
+    fn encode(self, value: i128) -> Option<Vec<u8>> {
+        match self {
+            VarintEncoding::Uleb128 => Some(encode_uleb128(u64::try_from(value).ok()?)),
+            VarintEncoding::Sleb128 => Some(encode_sleb128(i64::try_from(value).ok()?)),
+            VarintEncoding::PaddedUleb128(width) => {
+                pad_leb128(encode_uleb128(u64::try_from(value).ok()?), width, false)
+            }
+            VarintEncoding::PaddedSleb128(width) => {
+                pad_leb128(encode_sleb128(i64::try_from(value).ok()?), width, value < 0)
+            }
+        }
+    }
+    /// decodes a value from the start of the given bytes, returning it and its length.
+    fn decode(self, bytes: &[u8]) -> Option<(i128, usize)> {
+        match self {
+            VarintEncoding::Uleb128 | VarintEncoding::PaddedUleb128(_) => {
+                decode_uleb128(bytes).map(|(value, len)| (value as i128, len))
+            }
+            VarintEncoding::Sleb128 | VarintEncoding::PaddedSleb128(_) => {
+                decode_sleb128(bytes).map(|(value, len)| (value as i128, len))
+            }
+        }
+    }
+}
+
+/// a varint field in a buffer, which can be rewritten with a value of a different width.
+///
+/// content inserted exactly at either boundary of the field stays outside of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VarintField {
+    region: IndexRangeRef,
+    encoding: VarintEncoding,
+}
+impl VarintField {
+    /// the range ref of the encoded value.
+    pub fn region(&self) -> IndexRangeRef {
+        self.region
+    }
+    /// the encoding of the field.
+    pub fn encoding(&self) -> VarintEncoding {
+        self.encoding
+    }
+}
+
+impl IndexRefBuf<u8> {
+    /// inserts the given value as a minimal unsigned LEB128 integer at the given index, and returns its length.
+    pub fn insert_uleb128(&mut self, index: usize, value: u64) -> usize {
+        unwrap_or_panic(self.try_insert_uleb128(index, value))
+    }
+    /// like `insert_uleb128`, but returns an error instead of panicking.
+    pub fn try_insert_uleb128(&mut self, index: usize, value: u64) -> Result<usize, Error> {
+        let bytes = encode_uleb128(value);
+        self.try_insert_slice(index, &bytes)?;
+        Ok(bytes.len())
+    }
+    /// inserts the given value as a minimal signed LEB128 integer at the given index, and returns its length.
+    pub fn insert_sleb128(&mut self, index: usize, value: i64) -> usize {
+        unwrap_or_panic(self.try_insert_sleb128(index, value))
+    }
+    /// like `insert_sleb128`, but returns an error instead of panicking.
+    pub fn try_insert_sleb128(&mut self, index: usize, value: i64) -> Result<usize, Error> {
+        let bytes = encode_sleb128(value);
+        self.try_insert_slice(index, &bytes)?;
+        Ok(bytes.len())
+    }
+    /// reads an unsigned LEB128 integer at the given position, and returns its value and its length.
+    pub fn read_uleb128(&self, at: impl BufIndex) -> (u64, usize) {
+        unwrap_or_panic(self.try_read_uleb128(at))
+    }
+    /// like `read_uleb128`, but returns an error instead of panicking.
+    pub fn try_read_uleb128(&self, at: impl BufIndex) -> Result<(u64, usize), Error> {
+        let index = self.check_varint_index(at)?;
+        decode_uleb128(&self.buf[index..]).ok_or(Error::InvalidVarint { index })
+    }
+    /// reads a signed LEB128 integer at the given position, and returns its value and its length.
+    pub fn read_sleb128(&self, at: impl BufIndex) -> (i64, usize) {
+        unwrap_or_panic(self.try_read_sleb128(at))
+    }
+    /// like `read_sleb128`, but returns an error instead of panicking.
+    pub fn try_read_sleb128(&self, at: impl BufIndex) -> Result<(i64, usize), Error> {
+        let index = self.check_varint_index(at)?;
+        decode_sleb128(&self.buf[index..]).ok_or(Error::InvalidVarint { index })
+    }
+    /// inserts a varint field with the given value at the given index.
+    pub fn create_varint_field(
+        &mut self,
+        index: usize,
+        encoding: VarintEncoding,
+        value: i128,
+    ) -> VarintField {
+        unwrap_or_panic(self.try_create_varint_field(index, encoding, value))
+    }
+    /// like `create_varint_field`, but returns an error instead of panicking.
+    pub fn try_create_varint_field(
+        &mut self,
+        index: usize,
+        encoding: VarintEncoding,
+        value: i128,
+    ) -> Result<VarintField, Error> {
+        self.check_insertion_index(index)?;
+        if let VarintEncoding::PaddedUleb128(width) | VarintEncoding::PaddedSleb128(width) =
+            encoding
+        {
+            if width == 0 || width > MAX_LEB128_LEN {
+                return Err(Error::InvalidWidth { width });
+            }
+        }
+        let bytes = encode_varint(encoding, index, value)?;
+        self.try_insert_slice(index, &bytes)?;
+        let region = self.try_create_range_ref_with_gravity(
+            index..index + bytes.len(),
+            Gravity::Right,
+            Gravity::Left,
+        )?;
+        Ok(VarintField { region, encoding })
+    }
+    /// rewrites the value of the given varint field. if the width of the new value is different, the field is resized,
+    /// and the index refs after it are moved accordingly.
+    pub fn set_varint_field(&mut self, field: VarintField, value: i128) {
+        unwrap_or_panic(self.try_set_varint_field(field, value))
+    }
+    /// like `set_varint_field`, but returns an error instead of panicking.
+    pub fn try_set_varint_field(&mut self, field: VarintField, value: i128) -> Result<(), Error> {
+        let region = self.try_read_range_ref(field.region)?;
+        let bytes = encode_varint(field.encoding, region.start, value)?;
+        if bytes.len() == region.len() {
+            self.buf[region].copy_from_slice(&bytes);
+        } else {
+            self.try_splice(region, bytes)?;
+        }
+        Ok(())
+    }
+    /// reads the value of the given varint field.
+    pub fn read_varint_field(&self, field: VarintField) -> i128 {
+        unwrap_or_panic(self.try_read_varint_field(field))
+    }
+    /// like `read_varint_field`, but returns an error instead of panicking.
+    pub fn try_read_varint_field(&self, field: VarintField) -> Result<i128, Error> {
+        let region = self.try_read_range_ref(field.region)?;
+        field
+            .encoding
+            .decode(&self.buf[region.clone()])
+            .map(|(value, _)| value)
+            .ok_or(Error::InvalidVarint {
+                index: region.start,
+            })
+    }
+    /// resolves the position of a varint which is about to be read, checking that it is inside of the buffer.
+    fn check_varint_index(&self, at: impl BufIndex) -> Result<usize, Error> {
+        let index = at.resolve(self)?;
+        if index >= self.len() {
+            return Err(Error::OutOfBounds {
+                index,
+                len: self.len(),
+            });
+        }
+        Ok(index)
+    }
+}
+
+/// encodes the value of a varint field at the given index, or returns an error if it doesn't fit.
+fn encode_varint(encoding: VarintEncoding, index: usize, value: i128) -> Result<Vec<u8>, Error> {
+    encoding.encode(value).ok_or(Error::FixupOverflow {
+        site: index,
+        value,
+        width: match encoding {
+            VarintEncoding::PaddedUleb128(width) | VarintEncoding::PaddedSleb128(width) => width,
+            VarintEncoding::Uleb128 | VarintEncoding::Sleb128 => 10,
+        },
+    })
+}
+
+#[test]
+pub fn make_sure_varints_round_trip() {
+    let mut buf = IndexRefBuf::new();
+    assert_eq!(buf.insert_uleb128(0, 624485), 3);
+    assert_eq!(buf.insert_sleb128(3, -123456), 3);
+    assert_eq!(&buf[..], &[0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78]);
+    assert_eq!(buf.read_uleb128(0), (624485, 3));
+    assert_eq!(buf.read_sleb128(3), (-123456, 3));
+    buf.push(0x80);
+    assert_eq!(
+        buf.try_read_uleb128(6),
+        Err(Error::InvalidVarint { index: 6 })
+    );
+    for value in [0, 1, -1, 63, 64, -64, -65, i64::MIN, i64::MAX] {
+        let bytes = encode_sleb128(value);
+        assert_eq!(decode_sleb128(&bytes), Some((value, bytes.len())));
+    }
+    assert_eq!(
+        decode_uleb128(&encode_uleb128(u64::MAX)),
+        Some((u64::MAX, 10))
+    );
+}
+
+#[test]
+pub fn make_sure_varint_fields_are_resized_when_patched() {
+    let mut buf = IndexRefBuf::from_vec(vec![0xaa, 0xbb]);
+    let after = buf.create_index_ref(1);
+    let field = buf.create_varint_field(1, VarintEncoding::Uleb128, 5);
+    assert_eq!(&buf[..], &[0xaa, 5, 0xbb]);
+
+    buf.set_varint_field(field, 300);
+    assert_eq!(&buf[..], &[0xaa, 0xac, 0x02, 0xbb]);
+    assert_eq!(buf.read_index_ref(after), 3);
+    assert_eq!(buf.read_varint_field(field), 300);
+
+    let padded = buf.create_varint_field(0, VarintEncoding::PaddedSleb128(3), -2);
+    assert_eq!(&buf[..3], &[0xfe, 0xff, 0x7f]);
+    buf.set_varint_field(padded, 1000);
+    assert_eq!(buf.read_varint_field(padded), 1000);
+    assert_eq!(buf.read_index_ref(after), 6);
+    assert!(buf.try_set_varint_field(padded, 1 << 21).is_err());
+
+    // padding that can't be decoded is rejected.
+    assert_eq!(
+        buf.try_create_varint_field(0, VarintEncoding::PaddedUleb128(19), 0),
+        Err(Error::InvalidWidth { width: 19 })
+    );
+    let widest = buf.create_varint_field(0, VarintEncoding::PaddedSleb128(18), -1);
+    assert_eq!(buf.read_varint_field(widest), -1);
+}